@@ -43,6 +43,12 @@ options! {
 
     /// Enable output of trace!() macro
     trace: bool = true,
+
+    /// Log syscalls of all processes to serial, like strace
+    strace: bool = false,
+
+    /// Frequency of the scheduler tick, in Hz
+    hz: u64 = 100,
 }
 
 enum Arch {
@@ -75,6 +81,7 @@ fn output_makefile() {
 
 fn output_cargo_args() {
     let mut flags = vec![];
+    let mut vars = vec![];
 
     for (key, val) in OPTIONS {
         match *val {
@@ -82,6 +89,10 @@ fn output_cargo_args() {
             "true" => {
                 flags.push(format!("--cfg={}", key));
             }
+            // Numbers can't be expressed as cfgs, so they are passed through environment instead
+            _ if val.parse::<u64>().is_ok() => {
+                vars.push(format!("CFG_{}={}", key.to_uppercase(), val));
+            }
             _ => {
                 let leaf = val.split("::").last().unwrap();
                 flags.push(format!("--cfg={}_{}", key, leaf));
@@ -92,4 +103,8 @@ fn output_cargo_args() {
     let output = flags.into_iter().intersperse("\x1f".to_owned()).collect::<String>();
 
     print!("CARGO_ENCODED_RUSTFLAGS='{}'", output);
+
+    for var in vars {
+        print!(" {}", var);
+    }
 }
@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::mmu;
+use crate::mm::types::{PhysAddr, VirtAddr};
+use crate::mm::{pg_alloc, OutOfMemory};
+use crate::spinlock::Mutex;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NE: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+const CPUID_XSAVE: u32 = 1 << 26;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Size of the legacy area used by FXSAVE
+const FXSAVE_SIZE: usize = 512;
+
+const FCW_DEFAULT: u16 = 0x37f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// State currently loaded in the registers. Processes switch to it only when they use FPU for the
+/// first time after being scheduled, see `handle_device_not_available()`.
+static OWNER: Mutex<Option<FpuState>> = Mutex::new(None);
+
+/// Page holding FPU, SSE and AVX registers of a process while other processes use them
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FpuState {
+    addr: PhysAddr,
+}
+
+pub fn init() {
+    let mut cr0 = read_reg!(cr0);
+    cr0 &= !CR0_EM;
+    cr0 |= CR0_MP | CR0_NE;
+    write_reg!(cr0, cr0);
+
+    let mut cr4 = read_reg!(cr4);
+    cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+    let xsave = unsafe { __cpuid(1) }.ecx & CPUID_XSAVE != 0;
+
+    if xsave {
+        cr4 |= CR4_OSXSAVE;
+    }
+
+    write_reg!(cr4, cr4);
+
+    if xsave {
+        let leaf = unsafe { __cpuid_count(0xd, 0) };
+        let supported = u64::from(leaf.eax) | (u64::from(leaf.edx) << 32);
+        let xcr0 = supported & (XCR0_X87 | XCR0_SSE | XCR0_AVX);
+
+        xsetbv(xcr0);
+
+        // Size of the area for components enabled in XCR0, including legacy area and header
+        let size = unsafe { __cpuid_count(0xd, 0) }.ebx as usize;
+
+        assert!(size <= mmu::PAGE_SIZE, "fpu: XSAVE area doesn't fit in a page");
+
+        USE_XSAVE.store(true, Ordering::Relaxed);
+        STATE_SIZE.store(size, Ordering::Relaxed);
+    }
+
+    println_serial!(
+        "FPU state is saved with {} ({} bytes)",
+        if xsave { "XSAVE" } else { "FXSAVE" },
+        STATE_SIZE.load(Ordering::Relaxed)
+    );
+}
+
+impl FpuState {
+    pub fn alloc() -> Result<Self, OutOfMemory> {
+        let page = pg_alloc::alloc_page()?.inc_refc();
+        let state = FpuState {
+            addr: page.to_physaddr(),
+        };
+
+        // Page is zeroed, so XSAVE header says all components are in initial state. Control words
+        // are loaded regardless, so they need proper defaults with exceptions masked.
+        let area = state.area();
+
+        unsafe {
+            area.as_mut_ptr().cast::<u16>().write(FCW_DEFAULT);
+            area.as_mut_ptr().add(MXCSR_OFFSET).cast::<u32>().write(MXCSR_DEFAULT);
+        }
+
+        Ok(state)
+    }
+
+    /// Overwrite this state with another one, e.g. of a process being forked
+    pub fn copy_from(&self, other: &Self) {
+        // Registers hold newer values than memory if the state is loaded
+        if *OWNER.lock() == Some(*other) {
+            unsafe {
+                asm!("clts", options(nomem, nostack));
+            }
+
+            other.save();
+        }
+
+        self.area().copy_from_slice(other.area());
+    }
+
+    pub fn free(self) {
+        let mut owner = OWNER.lock();
+
+        if *owner == Some(self) {
+            *owner = None;
+        }
+
+        pg_alloc::perform_page_op(self.addr, |page| {
+            page.dec_refc();
+        });
+    }
+
+    fn area(&self) -> &'static mut [u8] {
+        let vaddr: VirtAddr = self.addr.into_vaddr();
+
+        unsafe { vaddr.into_slice_mut(STATE_SIZE.load(Ordering::Relaxed)) }
+    }
+
+    fn save(&self) {
+        let ptr = self.area().as_mut_ptr();
+
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX);
+            } else {
+                asm!("fxsave64 [{}]", in(reg) ptr);
+            }
+        }
+    }
+
+    fn restore(&self) {
+        let ptr = self.area().as_ptr();
+
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX);
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) ptr);
+            }
+        }
+    }
+}
+
+/// Arrange for the next FPU instruction to trap, unless `state` is already loaded
+pub fn switch_to(state: FpuState) {
+    let mut cr0 = read_reg!(cr0);
+
+    if *OWNER.lock() == Some(state) {
+        cr0 &= !CR0_TS;
+    } else {
+        cr0 |= CR0_TS;
+    }
+
+    write_reg!(cr0, cr0);
+}
+
+/// Swap state of the previous FPU user with `state` after an FPU instruction trapped
+pub fn handle_device_not_available(state: FpuState) {
+    let mut owner = OWNER.lock();
+
+    unsafe {
+        asm!("clts", options(nomem, nostack));
+    }
+
+    if let Some(prev) = *owner {
+        prev.save();
+    }
+
+    state.restore();
+
+    *owner = Some(state);
+}
+
+fn xsetbv(val: u64) {
+    unsafe {
+        asm!("xsetbv",
+            in("ecx") 0,
+            in("eax") val as u32,
+            in("edx") (val >> 32) as u32,
+            options(nomem, nostack));
+    }
+}
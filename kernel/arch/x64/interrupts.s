@@ -119,4 +119,5 @@ define_exception_handler 31
 define_irq_handler 0
 define_irq_handler 1
 define_irq_handler 2
+define_irq_handler 4
 define_irq_handler 8
@@ -16,7 +16,7 @@ static EXCEPTION_HANDLERS: [Exception; 32] = [
     Exception::stub_hdl("Overflow"),                                       // 4
     Exception::stub_hdl("Bound Range Exceeded"),                           // 5
     Exception::stub_hdl("Invalid Opcode"),                                 // 6
-    Exception::stub_hdl("Device Not Available"),                           // 7
+    Exception::with_hdl("Device Not Available", handlers::fpu_switch),     // 7
     Exception::stub_hdl("Double Fault"),                                   // 8
     Exception::stub_hdl("Coprocessor Segment Overrun"),                    // 9
     Exception::stub_hdl("Invalid TSS"),                                    // 10
@@ -45,11 +45,11 @@ static EXCEPTION_HANDLERS: [Exception; 32] = [
 
 struct Exception {
     name: &'static str,
-    handler: Option<fn(&ExceptionFrame)>,
+    handler: Option<fn(&ExceptionFrame) -> bool>,
 }
 
 impl Exception {
-    const fn with_hdl(name: &'static str, handler: fn(&ExceptionFrame)) -> Self {
+    const fn with_hdl(name: &'static str, handler: fn(&ExceptionFrame) -> bool) -> Self {
         Exception {
             name,
             handler: Some(handler),
@@ -211,11 +211,9 @@ impl fmt::Display for ExceptionFrame {
 
 impl mm::types::RegisterFrameOps for ExceptionFrame {
     fn new_userspace() -> Self {
-        let stack_top = arch::USER_STACK_START.0 + arch::USER_STACK_SIZE - 16;
         let intr_flag = 1 << 9;
 
         Self {
-            rsp: stack_top as u64,
             rflags: intr_flag,
             cs: (arch::GDT_USER_CODE | 3).into(),
             ss: (arch::GDT_USER_DATA | 3).into(),
@@ -227,21 +225,62 @@ impl mm::types::RegisterFrameOps for ExceptionFrame {
     fn set_program_counter(&mut self, addr: usize) {
         self.rip = addr as u64;
     }
+
+    fn set_stack_pointer(&mut self, addr: usize) {
+        self.rsp = addr as u64;
+    }
+
+    fn set_syscall_result(&mut self, val: u64) {
+        self.rax = val;
+    }
+}
+
+impl ExceptionFrame {
+    /// Whether the CPU was executing in ring 3 when the exception occured
+    pub fn is_from_userspace(&self) -> bool {
+        self.cs & 3 == 3
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn exception_dispatch(frame: &ExceptionFrame) {
+pub extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
     let vec = frame.number;
     let exc_handler = &EXCEPTION_HANDLERS[vec as usize];
+    let from_user = frame.is_from_userspace();
 
-    sched::current().registers = *frame;
+    if from_user {
+        sched::current().registers = *frame;
+    }
 
-    println!("Exception {} occured: {}", vec, exc_handler.name);
-    println!("{}", frame);
+    // Resolved exceptions return to the faulting instruction
+    if let Some(handler) = exc_handler.handler
+        && handler.call((&*frame,))
+    {
+        return;
+    }
+
+    let rip = frame.rip;
+    let cr2 = read_reg!(cr2);
 
-    if let Some(handler) = exc_handler.handler {
-        handler.call((frame,));
+    // Fault while accessing user memory on behalf of a process is reported back to the caller
+    if !from_user && let Some(fixup) = arch::uaccess::find_fixup(rip) {
+        frame.rip = fixup;
+        return;
     }
 
-    loop {}
+    if from_user {
+        let name = sched::current().name;
+
+        println!(
+            "Killed '{}': {} (exception {}) at RIP {:#x}, CR2 {:#x}",
+            name, exc_handler.name, vec, rip, cr2
+        );
+
+        sched::kill_current();
+    }
+
+    println!("Exception {} occured: {}", vec, exc_handler.name);
+    println!("{}", frame);
+
+    panic!("{} in kernel mode at RIP {:#x}, CR2 {:#x}", exc_handler.name, rip, cr2);
 }
@@ -3,25 +3,46 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::exceptions::ExceptionFrame;
-use crate::sched;
+use crate::arch::{self, fpu};
+use crate::mm::types::{RootPageDirOps, VirtAddr};
+use crate::mm::vma::Access;
 use crate::types::PowerOfTwoOps;
+use crate::{mm, sched};
 
 extern "C" {
     fn stack_guard_top();
     fn stack_guard_bot();
 }
 
-pub(super) fn divide_by_zero(_frame: &ExceptionFrame) {
+pub(super) fn divide_by_zero(_frame: &ExceptionFrame) -> bool {
     println!("Divide by zero handler");
+
+    false
 }
 
-pub(super) fn breakpoint(_frame: &ExceptionFrame) {
+pub(super) fn breakpoint(_frame: &ExceptionFrame) -> bool {
     // Usually this would be the place to enter a debugger, but the breakpoint exception is only
     // used to test interrupts in userspace.
     sched::next();
 }
 
-pub(super) fn page_fault(_frame: &ExceptionFrame) {
+pub(super) fn fpu_switch(frame: &ExceptionFrame) -> bool {
+    // Kernel is built without FPU and SSE, so the trap is expected only from userspace
+    if !frame.is_from_userspace() {
+        return false;
+    }
+
+    fpu::handle_device_not_available(sched::current().fpu_state);
+
+    true
+}
+
+/// Page fault error code bits
+const PF_PRESENT: u32 = 1 << 0;
+const PF_WRITE: u32 = 1 << 1;
+const PF_INSTRUCTION: u32 = 1 << 4;
+
+pub(super) fn page_fault(frame: &ExceptionFrame) -> bool {
     let vaddr = read_reg!(cr2);
     let round = vaddr.page_round_down() as usize;
     let guard_top_low = (stack_guard_top as usize) & 0xffffffff;
@@ -35,5 +56,52 @@ pub(super) fn page_fault(_frame: &ExceptionFrame) {
         panic!("Kernel stack underflow");
     }
 
-    println!("Faulting addr = {:#x}", vaddr);
+    if mm::kstack::is_guard_page(VirtAddr(vaddr as usize)) {
+        panic!("Process kernel stack overflow");
+    }
+
+    // Both user code and kernel, while copying to user memory, can touch pages that aren't
+    // allocated yet or write to copy-on-write ones. Any other fault in the kernel is a bug.
+    let error_code = frame.error_code;
+    let addr = VirtAddr(vaddr as usize);
+    let write = error_code & PF_WRITE != 0;
+
+    if addr >= arch::USER_SPACE_END {
+        return false;
+    }
+
+    if !frame.is_from_userspace() && arch::uaccess::find_fixup(frame.rip).is_none() {
+        return false;
+    }
+
+    let access = if error_code & PF_INSTRUCTION != 0 {
+        Access::Execute
+    } else if write {
+        Access::Write
+    } else {
+        Access::Read
+    };
+
+    let resolved = {
+        let mut proc = sched::current();
+        let proc = &mut *proc;
+
+        if error_code & PF_PRESENT == 0 {
+            proc.vmas.handle_fault(&mut proc.root_dir, addr, access)
+        } else if write {
+            proc.root_dir.copy_on_write(addr)
+        } else {
+            Ok(false)
+        }
+    };
+
+    // Retry the access after freeing some memory, unless the process is the one killed. If
+    // nothing can be freed, retrying would fault forever.
+    resolved.unwrap_or_else(|_| {
+        if !sched::oom_kill() {
+            sched::kill_current();
+        }
+
+        true
+    })
 }
@@ -15,6 +15,9 @@ static mut IDT: [IDTEntry; 256] = [IDTEntry {
     reserved: 0,
 }; 256];
 
+/// Double fault is likely to be caused by kernel stack overflow, so it needs a known good stack
+const DOUBLE_FAULT_IST: u16 = 1;
+
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 struct IDTEntry {
@@ -65,6 +68,7 @@ fn build() {
         fn handle_irq_0();
         fn handle_irq_1();
         fn handle_irq_2();
+        fn handle_irq_4();
         fn handle_irq_8();
     }
 
@@ -77,7 +81,7 @@ fn build() {
         IDT[5] = create_idt_entry(handle_exception_5, true, true);
         IDT[6] = create_idt_entry(handle_exception_6, true, true);
         IDT[7] = create_idt_entry(handle_exception_7, true, true);
-        IDT[8] = create_idt_entry(handle_exception_8, true, true);
+        IDT[8] = create_idt_entry(handle_exception_8, true, true).with_ist(DOUBLE_FAULT_IST);
         IDT[9] = create_idt_entry(handle_exception_9, true, true);
         IDT[10] = create_idt_entry(handle_exception_10, true, true);
         IDT[11] = create_idt_entry(handle_exception_11, true, true);
@@ -105,10 +109,19 @@ fn build() {
         IDT[32] = create_idt_entry(handle_irq_0, true, false);
         IDT[33] = create_idt_entry(handle_irq_1, true, false);
         IDT[34] = create_idt_entry(handle_irq_2, true, false);
+        IDT[36] = create_idt_entry(handle_irq_4, true, false);
         IDT[40] = create_idt_entry(handle_irq_8, true, false);
     }
 }
 
+impl IDTEntry {
+    /// Switch to a stack from Interrupt Stack Table, even if the CPU is already in ring 0
+    const fn with_ist(mut self, ist: u16) -> Self {
+        self.attributes |= ist;
+        self
+    }
+}
+
 fn create_idt_entry(
     handler: unsafe extern "C" fn(),
     kernel_only: bool,
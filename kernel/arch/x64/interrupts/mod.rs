@@ -8,15 +8,17 @@ pub mod exceptions;
 mod handlers;
 mod idt;
 mod pic;
-mod rtc;
+mod pit;
+pub mod rtc;
 
 pub fn init() {
     pic::remap();
     idt::init();
-    rtc::init();
+    pit::init();
 
+    pic::enable_line(0);
     pic::enable_line(2);
-    pic::enable_line(8);
+    pic::enable_line(4);
 }
 
 #[inline(always)]
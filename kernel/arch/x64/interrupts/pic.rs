@@ -3,9 +3,9 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::exceptions::ExceptionFrame;
-use super::rtc;
+use super::pit;
 use crate::arch::asm::io;
-use crate::sched;
+use crate::{sched, serial, timer};
 
 const PIC_IRQ_OFFSET: u8 = 32;
 const PIC1: u16 = 0x20;
@@ -93,10 +93,26 @@ fn irq_eoi(irq: u8) {
 pub extern "C" fn irq_dispatch(frame: &ExceptionFrame) {
     let vec = frame.number as u8;
 
-    sched::current().registers = *frame;
+    // Interrupts can also arrive while idling in the kernel
+    if frame.is_from_userspace() {
+        sched::current().registers = *frame;
+    }
+
+    if vec == 4 {
+        let woken = serial::handle_interrupt();
+        irq_eoi(vec);
+
+        // Let the reader run right away instead of waiting for the next tick
+        if woken {
+            sched::next();
+        }
+
+        return;
+    }
 
-    if vec == 8 {
-        rtc::handle_interrupt();
+    if vec == 0 {
+        pit::handle_interrupt();
+        timer::run_expired();
         irq_eoi(vec);
         sched::next();
     }
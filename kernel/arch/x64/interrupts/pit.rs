@@ -0,0 +1,33 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::arch::asm::io;
+use crate::time;
+
+const PORT_CHANNEL0: u16 = 0x40;
+const PORT_CMND: u16 = 0x43;
+
+/// Frequency of the oscillator driving the PIT
+const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0: u8 = 0b00 << 6;
+const ACCESS_LOHI: u8 = 0b11 << 4;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+pub(super) fn init() {
+    let divisor = (BASE_FREQUENCY + time::HZ / 2) / time::HZ;
+
+    assert!((1..=0x10000).contains(&divisor), "pit: HZ is out of range");
+
+    // Divisor of 0x10000 is written as 0
+    let divisor = divisor as u16;
+
+    io::outb(PORT_CMND, CHANNEL0 | ACCESS_LOHI | MODE_RATE_GENERATOR);
+    io::outb(PORT_CHANNEL0, divisor as u8);
+    io::outb(PORT_CHANNEL0, (divisor >> 8) as u8);
+}
+
+pub(super) fn handle_interrupt() {
+    time::tick();
+}
@@ -2,26 +2,35 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use core::fmt;
+
 use crate::arch::asm;
 
 const PORT_CMND: u16 = 0x70;
 const PORT_DATA: u16 = 0x71;
 
+const REG_SECONDS: u8 = 0x0;
+const REG_MINUTES: u8 = 0x2;
+const REG_HOURS: u8 = 0x4;
+const REG_DAY: u8 = 0x7;
+const REG_MONTH: u8 = 0x8;
+const REG_YEAR: u8 = 0x9;
 const REG_A: u8 = 0xa;
 const REG_B: u8 = 0xb;
-const REG_C: u8 = 0xc;
-
-const INTERRUPT_ENABLE: u8 = 1 << 6;
-
-pub(super) fn init() {
-    asm::nmi_disable();
-
-    let prev = read_register(REG_B);
-    write_register(REG_B, prev | INTERRUPT_ENABLE);
 
-    set_frequency();
-
-    asm::nmi_enable();
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const FORMAT_24_HOUR: u8 = 1 << 1;
+const FORMAT_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
 }
 
 fn read_register(reg: u8) -> u8 {
@@ -29,26 +38,69 @@ fn read_register(reg: u8) -> u8 {
     asm::io::inb(PORT_DATA)
 }
 
-fn write_register(reg: u8, val: u8) {
-    asm::io::outb(PORT_CMND, reg);
-    asm::io::outb(PORT_DATA, val);
-}
+/// Read wall-clock time. Assumes the RTC is set to UTC and the current century is 21st.
+pub fn read_time() -> DateTime {
+    // Registers can change between reads, so read until two results agree
+    let mut time = read_raw_time();
+
+    loop {
+        let again = read_raw_time();
 
-fn set_frequency() {
-    // NMIs need to be disabled
+        if again == time {
+            break;
+        }
 
-    let rate = 15; // 2 Hz
+        time = again;
+    }
 
-    let prev = read_register(REG_A);
-    write_register(REG_A, (prev & 0b11110000) | rate);
+    let format = read_register(REG_B);
+
+    if format & FORMAT_BINARY == 0 {
+        let pm = time.hours & HOUR_PM;
+
+        time.seconds = from_bcd(time.seconds);
+        time.minutes = from_bcd(time.minutes);
+        time.hours = from_bcd(time.hours & !HOUR_PM) | pm;
+        time.day = from_bcd(time.day);
+        time.month = from_bcd(time.month);
+        time.year = from_bcd(time.year as u8).into();
+    }
+
+    // In 12-hour mode, midnight is 12 AM and noon is 12 PM
+    if format & FORMAT_24_HOUR == 0 {
+        let hours = (time.hours & !HOUR_PM) % 12;
+
+        time.hours = if time.hours & HOUR_PM != 0 { hours + 12 } else { hours };
+    }
+
+    time.year += 2000;
+
+    time
 }
 
-pub(super) fn handle_interrupt() {
-    trace!("tick");
+fn read_raw_time() -> DateTime {
+    while read_register(REG_A) & UPDATE_IN_PROGRESS != 0 {}
+
+    DateTime {
+        year: read_register(REG_YEAR).into(),
+        month: read_register(REG_MONTH),
+        day: read_register(REG_DAY),
+        hours: read_register(REG_HOURS),
+        minutes: read_register(REG_MINUTES),
+        seconds: read_register(REG_SECONDS),
+    }
+}
 
-    eoi();
+fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0xf)
 }
 
-fn eoi() {
-    read_register(REG_C);
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hours, self.minutes, self.seconds
+        )
+    }
 }
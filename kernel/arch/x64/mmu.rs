@@ -5,9 +5,8 @@
 use core::slice;
 
 use crate::arch::{self, LeafDirEntry, LeafDirEntryLarge};
-use crate::bootloader::BootloaderInfo;
-use crate::mm::pg_alloc;
 use crate::mm::types::{Address, PhysAddr, RootPageDirOps, VirtAddr};
+use crate::mm::{self, pg_alloc, OutOfMemory};
 use crate::types::{Bytes, KiB, MiB, PowerOfTwoOps};
 
 pub const PAGE_SIZE: usize = KiB(4).to_bytes();
@@ -16,10 +15,18 @@ pub const PAGE_SIZE_LARGE: usize = MiB(2).to_bytes();
 /// Number of entries in a directory of any level (PML4, PDPT, PD, PT). Equal to 4096 B / 64 b.
 const ENTRIES: usize = 512;
 
+/// First top-level entry of the higher half, which belongs to the kernel
+const KERNEL_HALF_START: usize = ENTRIES / 2;
+
+/// Bits of an entry that hold physical address. The rest are flags.
+const ADDR_MASK: usize = 0xffffffffff000;
+
 pub const PRESENT: usize = 1 << 0;
 pub const WRITABLE: usize = 1 << 1;
 pub const USER_ACCESSIBLE: usize = 1 << 2;
 pub const LARGE: usize = 1 << 7;
+/// Ignored by the CPU. Marks pages that are shared after fork and are copied on first write.
+pub const COPY_ON_WRITE: usize = 1 << 9;
 pub const NON_EXECUTABLE: usize = 1 << 63;
 
 #[derive(Clone, Copy)]
@@ -75,9 +82,14 @@ trait DirectoryEntry: SetScalar + Into<u64> {
         self.into() & PRESENT as u64 != 0
     }
 
+    /// Whether this entry maps a large page instead of pointing to a directory
+    fn large(self) -> bool {
+        self.into() & LARGE as u64 != 0
+    }
+
     /// Get the address of directory this entry points to
     fn pointed_addr(self) -> PhysAddr {
-        let paddr = self.into() & 0xffffffffff000;
+        let paddr = self.into() & ADDR_MASK as u64;
         PhysAddr::from_u64(paddr)
     }
 
@@ -92,13 +104,33 @@ trait DirectoryEntry: SetScalar + Into<u64> {
         unsafe { slice::from_raw_parts_mut(ptr, ENTRIES) }
     }
 
-    fn create_entry(&mut self) {
-        let dir = pg_alloc::alloc_page().inc_refc();
+    fn flags(self) -> usize {
+        self.into() as usize & !ADDR_MASK
+    }
+
+    /// Add a reference to the page this entry points to
+    fn share_pointed(self) {
+        pg_alloc::perform_page_op(self.pointed_addr(), |page| {
+            page.inc_refc();
+        });
+    }
+
+    /// Drop a reference to the page this entry points to
+    fn release_pointed(self) {
+        pg_alloc::perform_page_op(self.pointed_addr(), |page| {
+            page.dec_refc();
+        });
+    }
+
+    fn create_entry(&mut self) -> Result<(), OutOfMemory> {
+        let dir = pg_alloc::alloc_page()?.inc_refc();
 
         // TODO: improve permissions, especially USER_ACCESSIBLE part
         let perms = WRITABLE | PRESENT | USER_ACCESSIBLE;
 
         self.set_scalar(dir.to_physaddr().0 | perms);
+
+        Ok(())
     }
 }
 
@@ -181,37 +213,23 @@ impl ToFrames for VirtAddr {
 }
 
 impl RootPageDirOps for PageMapLevel4 {
-    fn new() -> Self {
-        let dir = pg_alloc::alloc_page().inc_refc();
+    fn new() -> Result<Self, OutOfMemory> {
+        let dir = pg_alloc::alloc_page()?.inc_refc();
         let phys = dir.to_physaddr();
-        PageMapLevel4 { addr: phys }
+        Ok(PageMapLevel4 { addr: phys })
     }
 
-    fn new_userspace(info: &BootloaderInfo) -> Self {
-        let mut dir = Self::new();
+    fn new_userspace() -> Result<Self, OutOfMemory> {
+        let mut dir = Self::new()?;
+        let mut kern_dir = mm::kernel_root_dir();
 
-        // Number is chosen arbitrarily
-        dir.map_region_large(
-            VirtAddr(arch::KERNEL_BASE),
-            PhysAddr(0),
-            64,
-            PRESENT | WRITABLE | USER_ACCESSIBLE,
-        );
-
-        let fb = &info.framebuffer;
-        let fb_addr = PhysAddr::from_u64(fb.addr);
-        let fb_size = fb.pitch * fb.height * u32::from(fb.bpp) / 8;
-        let pages = fb_size as usize / PAGE_SIZE_LARGE;
-        dir.map_region_large(
-            fb_addr.into_vaddr(),
-            fb_addr,
-            pages,
-            PRESENT | WRITABLE | USER_ACCESSIBLE,
-        );
-
-        dir.alloc_range(arch::USER_STACK_START, arch::USER_STACK_SIZE, WRITABLE | USER_ACCESSIBLE);
+        // Kernel half is shared with the kernel root directory by pointing to the same lower level
+        // tables. Mappings there are supervisor-only, so user code faults when touching them. Note
+        // that top-level entries added to the kernel root directory later won't show up here.
+        dir.as_slice_mut()[KERNEL_HALF_START..]
+            .copy_from_slice(&kern_dir.as_slice_mut()[KERNEL_HALF_START..]);
 
-        dir
+        Ok(dir)
     }
 
     fn switch_to_this(&self) {
@@ -225,7 +243,7 @@ impl RootPageDirOps for PageMapLevel4 {
 
         if !pml4e.present() {
             if create {
-                pml4e.create_entry();
+                pml4e.create_entry().ok()?;
             } else {
                 return None;
             }
@@ -236,7 +254,7 @@ impl RootPageDirOps for PageMapLevel4 {
 
         if !pdpe.present() {
             if create {
-                pdpe.create_entry();
+                pdpe.create_entry().ok()?;
             } else {
                 return None;
             }
@@ -247,7 +265,7 @@ impl RootPageDirOps for PageMapLevel4 {
 
         if !pde.present() {
             if create {
-                pde.create_entry();
+                pde.create_entry().ok()?;
             } else {
                 return None;
             }
@@ -256,12 +274,9 @@ impl RootPageDirOps for PageMapLevel4 {
         let pt = pde.pointed_dir();
         let pte = &mut pt[frames.pt_offset];
 
-        if !pte.present() {
-            if create {
-                pte.create_entry();
-            } else {
-                return None;
-            }
+        // Leaf entry is filled in by the caller, so it isn't created here
+        if !pte.present() && !create {
+            return None;
         }
 
         Some(pte)
@@ -273,7 +288,7 @@ impl RootPageDirOps for PageMapLevel4 {
 
         if !pml4e.present() {
             if create {
-                pml4e.create_entry();
+                pml4e.create_entry().ok()?;
             } else {
                 return None;
             }
@@ -284,7 +299,7 @@ impl RootPageDirOps for PageMapLevel4 {
 
         if !pdpe.present() {
             if create {
-                pdpe.create_entry();
+                pdpe.create_entry().ok()?;
             } else {
                 return None;
             }
@@ -293,32 +308,27 @@ impl RootPageDirOps for PageMapLevel4 {
         let pdt = pdpe.pointed_dir();
         let pde = &mut pdt[frames.pd_offset];
 
-        if !pde.present() {
-            if create {
-                pde.create_entry();
-            } else {
-                return None;
-            }
+        if !pde.present() && !create {
+            return None;
         }
 
         Some(pde)
     }
 
-    fn map_page_at_addr(&mut self, page: &mut pg_alloc::PageInfo, addr: VirtAddr, perms: usize) {
-        if let Some(pte) = self.walk_dir(addr, false) {
-            pg_alloc::perform_page_op(pte.pointed_addr(), |page| {
-                page.dec_refc();
-            });
-            pte.set_scalar(pte.scalar as usize & !PRESENT);
-            arch::asm::invalidate_dcache(addr);
-        }
+    fn map_page_at_addr(
+        &mut self,
+        page: &mut pg_alloc::PageInfo,
+        addr: VirtAddr,
+        perms: usize,
+    ) -> Result<(), OutOfMemory> {
+        self.unmap_page_at_addr(addr);
 
-        page.inc_refc();
-
-        let pte = self.walk_dir(addr, true).unwrap();
-        let addr = page.to_physaddr().0 | perms | PRESENT;
+        let pte = self.walk_dir(addr, true).ok_or(OutOfMemory)?;
+        let addr = page.inc_refc().to_physaddr().0 | perms | PRESENT;
 
         pte.set_scalar(addr);
+
+        Ok(())
     }
 
     fn unmap_page_at_addr(&mut self, addr: VirtAddr) {
@@ -412,22 +422,153 @@ impl RootPageDirOps for PageMapLevel4 {
         for page in (from.0..from.0 + size).step_by(PAGE_SIZE) {
             let vaddr = VirtAddr(page);
             if let Some(pte) = self.walk_dir(vaddr, false) {
-                let paddr = pte.pointed_addr().0;
-                pte.set_scalar(paddr | perms);
+                let paddr = pte.pointed_addr();
+                let mut perms = perms;
+
+                // Page shared after fork can only become writable once it's copied
+                if perms & WRITABLE != 0 {
+                    pg_alloc::perform_page_op(paddr, |page| {
+                        if page.refc() > 1 {
+                            perms = (perms & !WRITABLE) | COPY_ON_WRITE;
+                        }
+                    });
+                }
+
+                pte.set_scalar(paddr.0 | perms);
+                arch::asm::invalidate_dcache(vaddr);
             }
         }
     }
 
-    fn is_region_user_accessible(&mut self, from: VirtAddr, to: VirtAddr) -> bool {
-        for page in (from.page_round_down().0..to.page_round_up().0).step_by(PAGE_SIZE) {
-            let vaddr = VirtAddr(page);
-            if let Some(pte) = self.walk_dir(vaddr, false) {
-                if pte.scalar as usize & USER_ACCESSIBLE == 0 {
-                    return false;
+    fn fork(&mut self) -> Result<Self, OutOfMemory> {
+        let mut child = Self::new_userspace()?;
+        let user_half = self.as_slice_mut()[..KERNEL_HALF_START].iter().enumerate();
+
+        for (pml4_idx, &pml4e) in user_half.filter(|(_, e)| e.present()) {
+            let pdpt = pml4e.pointed_dir().iter().enumerate();
+
+            for (pdpt_idx, &pdpe) in pdpt.filter(|(_, e)| e.present()) {
+                let pdt = pdpe.pointed_dir().iter().enumerate();
+
+                for (pd_idx, &pde) in pdt.filter(|(_, e)| e.present() && !e.large()) {
+                    let pt = pde.pointed_dir().iter_mut().enumerate();
+
+                    for (pt_idx, pte) in pt.filter(|(_, e)| e.present()) {
+                        let vaddr =
+                            (pml4_idx << 39) | (pdpt_idx << 30) | (pd_idx << 21) | (pt_idx << 12);
+                        let Some(child_pte) = child.walk_dir(VirtAddr(vaddr), true) else {
+                            child.release();
+                            return Err(OutOfMemory);
+                        };
+                        let flags = pte.flags();
+
+                        // Both processes keep reading the same page until one of them writes to it
+                        if flags & WRITABLE != 0 {
+                            let addr = pte.pointed_addr().0;
+                            pte.set_scalar(addr | (flags & !WRITABLE) | COPY_ON_WRITE);
+                        }
+
+                        pte.share_pointed();
+
+                        child_pte.set_scalar(pte.scalar as usize);
+                    }
                 }
             }
         }
 
-        true
+        // Flush TLB, since parent's pages are no longer writable
+        let cr3 = read_reg!(cr3);
+
+        write_reg!(cr3, cr3);
+
+        Ok(child)
+    }
+
+    fn copy_on_write(&mut self, addr: VirtAddr) -> Result<bool, OutOfMemory> {
+        if addr >= arch::USER_SPACE_END {
+            return Ok(false);
+        }
+
+        let Some(pte) = self.walk_dir(addr, false) else {
+            return Ok(false);
+        };
+
+        let flags = pte.flags();
+
+        if flags & COPY_ON_WRITE == 0 {
+            return Ok(false);
+        }
+
+        let perms = (flags & !COPY_ON_WRITE) | WRITABLE;
+        let old = pte.pointed_addr();
+        let mut shared = false;
+
+        pg_alloc::perform_page_op(old, |page| {
+            shared = page.refc() > 1;
+        });
+
+        // Last user of the page can simply take it over
+        if shared {
+            let new = pg_alloc::alloc_page()?.inc_refc().to_physaddr();
+
+            unsafe {
+                let src = old.into_vaddr().into_slice_mut(PAGE_SIZE);
+                let dst = new.into_vaddr().into_slice_mut(PAGE_SIZE);
+
+                dst.copy_from_slice(src);
+            }
+
+            pte.set_scalar(new.0 | perms);
+
+            pg_alloc::perform_page_op(old, |page| {
+                page.dec_refc();
+            });
+        } else {
+            pte.set_scalar(old.0 | perms);
+        }
+
+        arch::asm::invalidate_dcache(addr);
+
+        Ok(true)
+    }
+
+    fn resident_pages(&mut self) -> usize {
+        let mut pages = 0;
+
+        for &pml4e in self.as_slice_mut()[..KERNEL_HALF_START].iter().filter(|e| e.present()) {
+            for &pdpe in pml4e.pointed_dir().iter().filter(|e| e.present()) {
+                for &pde in pdpe.pointed_dir().iter().filter(|e| e.present() && !e.large()) {
+                    pages += pde.pointed_dir().iter().filter(|e| e.present()).count();
+                }
+            }
+        }
+
+        pages
+    }
+
+    fn release(&mut self) {
+        // Kernel half is borrowed from the kernel root directory and large pages only ever map
+        // memory not owned by this directory. Everything else was allocated through `pg_alloc`.
+        for &pml4e in self.as_slice_mut()[..KERNEL_HALF_START].iter().filter(|e| e.present()) {
+            for &pdpe in pml4e.pointed_dir().iter().filter(|e| e.present()) {
+                for &pde in pdpe.pointed_dir().iter().filter(|e| e.present() && !e.large()) {
+                    for &pte in pde.pointed_dir().iter().filter(|e| e.present()) {
+                        pte.release_pointed();
+                    }
+
+                    pde.release_pointed();
+                }
+
+                pdpe.release_pointed();
+            }
+
+            pml4e.release_pointed();
+        }
+
+        pg_alloc::perform_page_op(self.addr, |page| {
+            page.dec_refc();
+        });
+
+        self.addr = PhysAddr(0);
     }
 }
@@ -3,6 +3,8 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{mem, ptr};
 
 use crate::mm::types::{RootPageDirOps, VirtAddr};
@@ -11,19 +13,43 @@ use crate::process::Process;
 #[macro_use]
 pub mod asm;
 pub mod backtrace;
+pub mod fpu;
 pub mod interrupts;
 pub mod mmu;
+pub mod uaccess;
 pub mod uart;
 
 pub const KERNEL_BASE: usize = 0xffffff8000000000;
+pub const KERNEL_STACKS_BASE: VirtAddr = VirtAddr(0xffffff0000000000);
+pub const KERNEL_HEAP_BASE: VirtAddr = VirtAddr(0xfffffe8000000000);
 
+/// End of the lower half of address space, available to userspace
+pub const USER_SPACE_END: VirtAddr = VirtAddr(0x0000800000000000);
+
+/// Lowest possible address of the guard page below the user stack
 pub const USER_STACK_START: VirtAddr = VirtAddr(0x0000001000000000);
+/// Initial size of the user stack. It grows on faults up to the maximum size.
 pub const USER_STACK_SIZE: usize = 4 * mmu::PAGE_SIZE;
+pub const USER_STACK_MAX_SIZE: usize = 256 * mmu::PAGE_SIZE;
+
+/// Lowest possible address of thread-local storage of a process
+pub const USER_TLS_START: VirtAddr = VirtAddr(0x0000003000000000);
+pub const USER_TLS_MAX_SIZE: usize = 16 * mmu::PAGE_SIZE;
+
+/// Lowest possible base of position-independent programs
+pub const USER_PIE_BASE: VirtAddr = VirtAddr(0x0000550000000000);
+
+/// Lowest address anonymous mappings are placed at when a program doesn't ask for one
+pub const USER_MMAP_START: VirtAddr = VirtAddr(0x0000700000000000);
+
+/// Number of pages the stack and program base are randomly shifted within
+pub const USER_ASLR_PAGES: usize = 1 << 24;
 
 pub const EMPTY_ROOT_DIR: RootPageDir = mmu::PageMapLevel4::empty();
 
 pub type RegisterFrame = interrupts::exceptions::ExceptionFrame;
 pub type RootPageDir = mmu::PageMapLevel4;
+pub type FpuState = fpu::FpuState;
 pub type LeafDirEntry = mmu::PageTableEntry;
 pub type LeafDirEntryLarge = mmu::PageDirectoryEntry;
 
@@ -36,6 +62,8 @@ pub(self) const GDT_TSS_TOP: u16 = 48;
 
 static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
+static RDRAND_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
 #[repr(C, packed)]
 struct TaskStateSegment {
     res1: u32,
@@ -61,20 +89,93 @@ impl TaskStateSegment {
     }
 }
 
+/// Callee-saved state of a process that was suspended while executing in the kernel. Registers
+/// themselves are stored on the kernel stack of the process.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct KernelContext {
+    rsp: u64,
+}
+
 pub fn init() {
     extern "C" {
         fn int_stack_botmost();
-        fn priv_stack_bot();
     }
 
     unsafe {
+        // Used only for double faults, see `idt::build()`
         TSS.ist[0] = int_stack_botmost as usize as u64;
-        TSS.rsp[0] = priv_stack_bot as usize as u64;
 
         load_tss(&TSS);
     }
 
     set_syscall_msrs();
+
+    enable_protection_features();
+
+    detect_rdrand();
+
+    fpu::init();
+}
+
+fn detect_rdrand() {
+    let cpuid_rdrand = 1 << 30;
+
+    if unsafe { __cpuid(1) }.ecx & cpuid_rdrand != 0 {
+        RDRAND_SUPPORTED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Get a random number from the CPU. Without RDRAND it's derived from the timestamp counter, which
+/// is only good enough to make values differ between calls and boots.
+pub fn random_u64() -> u64 {
+    let mut val = 0;
+
+    if RDRAND_SUPPORTED.load(Ordering::Relaxed) {
+        // RDRAND can run out of entropy for a moment, in which case it's recommended to retry
+        for _ in 0..10 {
+            if unsafe { _rdrand64_step(&mut val) } == 1 {
+                return val;
+            }
+        }
+    }
+
+    // splitmix64 finalizer
+    val = unsafe { _rdtsc() }.wrapping_add(0x9e3779b97f4a7c15);
+    val = (val ^ (val >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    val = (val ^ (val >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    val ^ (val >> 31)
+}
+
+/// Prevent the kernel from executing (SMEP) and accessing (SMAP) user pages, if CPU supports that
+fn enable_protection_features() {
+    let cpuid_smep = 1 << 7;
+    let cpuid_smap = 1 << 20;
+    let cr4_smep = 1 << 20;
+    let cr4_smap = 1 << 21;
+
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+
+    if max_leaf < 7 {
+        return;
+    }
+
+    let features = unsafe { __cpuid_count(7, 0) }.ebx;
+    let mut cr4 = read_reg!(cr4);
+
+    if features & cpuid_smep != 0 {
+        println_serial!("Enabling SMEP");
+        cr4 |= cr4_smep;
+    }
+
+    if features & cpuid_smap != 0 {
+        println_serial!("Enabling SMAP");
+        cr4 |= cr4_smap;
+        uaccess::SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+
+    write_reg!(cr4, cr4);
 }
 
 fn load_tss(tss: &TaskStateSegment) {
@@ -168,9 +269,27 @@ pub fn switch_to_process(proc: Process) -> ! {
 
     proc.root_dir.switch_to_this();
 
+    set_kernel_stack(proc.kernel_stack.top());
+
+    fpu::switch_to(proc.fpu_state);
+
+    set_thread_pointer(proc.thread_pointer);
+
+    if let Some(context) = proc.kernel_context {
+        restore_context(context);
+    }
+
     do_switch(&proc.registers);
 }
 
+/// Point FS segment to the thread control block. Without FSGSBASE instructions enabled, processes
+/// can't change FS base themselves, so it doesn't have to be saved when they are switched from.
+fn set_thread_pointer(addr: VirtAddr) {
+    let fs_base_msr = 0xc000_0100;
+
+    asm::wrmsr(fs_base_msr, addr.0 as u64);
+}
+
 #[naked]
 extern "C" fn do_switch(registers: &RegisterFrame) -> ! {
     unsafe {
@@ -222,3 +341,73 @@ extern "C" fn do_switch(registers: &RegisterFrame) -> ! {
         );
     }
 }
+
+/// Set stack used on transitions from userspace, both through interrupts and syscalls
+fn set_kernel_stack(top: VirtAddr) {
+    extern "C" {
+        static mut sysc_kernel_rsp: u64;
+    }
+
+    unsafe {
+        TSS.rsp[0] = top.0 as u64;
+        sysc_kernel_rsp = top.0 as u64;
+    }
+}
+
+/// Suspend execution at this point and call `f` with the context it can be resumed from. The
+/// function returns once the context is passed to `restore_context()`.
+#[naked]
+pub extern "C" fn save_context(f: extern "C" fn(KernelContext) -> !) {
+    unsafe {
+        asm!(
+            "push rbp",
+            "push rbx",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // Return address and six registers leave the stack misaligned for the call
+            "sub rsp, 8",
+            "mov rax, rdi",
+            "mov rdi, rsp",
+            "call rax",
+            "ud2",
+            options(noreturn)
+        );
+    }
+}
+
+#[naked]
+extern "C" fn restore_context(context: KernelContext) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, rdi",
+            "add rsp, 8",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbx",
+            "pop rbp",
+            "ret",
+            options(noreturn)
+        );
+    }
+}
+
+/// Wait for interrupts on the boot stack, which is free after `kmain` hands control to the
+/// scheduler. Starting from the top each time keeps repeated idling from exhausting it.
+pub fn idle() -> ! {
+    extern "C" {
+        fn stack_botmost();
+    }
+
+    idle_on_stack(stack_botmost as usize as u64);
+}
+
+#[naked]
+extern "C" fn idle_on_stack(stack: u64) -> ! {
+    unsafe {
+        asm!("mov rsp, rdi", "sti", "2:", "hlt", "jmp 2b", options(noreturn));
+    }
+}
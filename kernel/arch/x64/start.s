@@ -6,13 +6,9 @@ global start
 global gdt
 global stack_guard_top
 global stack_guard_bot
+global stack_botmost
 global int_stack_botmost
 global int_stack_guard_bot
-global priv_stack_bot
-global priv_stack_guard_bot
-global sysc_stack_bot
-global sysc_stack_guard_bot
-global sysc_saved_rsp
 global mb_info
 
 extern kmain
@@ -257,7 +253,8 @@ hltspin:
 	hlt
 	jmp hltspin
 
-section .rodata
+; Writable, because TSS descriptor is filled in at runtime and then marked busy by the CPU
+section .data
 gdt:
 %define RW (1 << 41) ; Readable (for code) / Writable (for data)
 %define Ex (1 << 43) ; Executable
@@ -299,18 +296,6 @@ int_stack_topmost:
 int_stack_botmost:
 int_stack_guard_bot:
 	resb 4096
-priv_stack_top:
-	resb KERNEL_INT_STACK_SZ
-priv_stack_bot:
-priv_stack_guard_bot:
-	resb 4096
-sysc_stack_top:
-	resb KERNEL_INT_STACK_SZ
-sysc_stack_bot:
-sysc_stack_guard_bot:
-	resb 4096
-sysc_saved_rsp:
-	resq 1
 mb_info:
 	resq 1
 
@@ -3,15 +3,17 @@
 ; file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 global syscall_handler
-global sysc_stack_guard_bot
+global sysc_kernel_rsp
 
-extern sysc_stack_bot
-extern sysc_saved_rsp
 extern syscall_dispatch
 
+%define GDT_USER_DATA 24
+%define GDT_USER_CODE 32
+
 ; If, in Rust, pointers could be "cast to integers during const eval", then this could've been
 ; written as a naked function, akin to `do_switch()`.
 
+section .text
 syscall_handler:
 	; State provided by `syscall` instruction:
 	;   rcx = retaddr
@@ -23,17 +25,23 @@ syscall_handler:
 	; Syscall args: rdi rsi rdx r10 (r10 instead of rcx)
 	; Syscall num:  rax
 
-	; Reuse the `RegisterFrame` struct
-	push 0             ; SS (can't push SS, CS)
-	push rsp           ; user RSP
-	add qword [rsp], 8 ; adjust RSP
-	push r11           ; RFLAGS
-	push 0             ; CS
-	push rcx           ; RIP
-	push 0             ; vector and error code (unused)
+	; Switch to the kernel stack of current process. Interrupts are masked through SFMASK, so the
+	; scratch slot for user RSP can't be overwritten until it's pushed. This should be done through
+	; gs instead once there's more than one CPU.
+	mov [rel sysc_user_rsp], rsp
+	mov rsp, [rel sysc_kernel_rsp]
+
+	; Reuse the `RegisterFrame` struct. Process is resumed from it if it gets rescheduled during
+	; the syscall.
+	push GDT_USER_DATA | 3          ; SS
+	push qword [rel sysc_user_rsp]  ; user RSP
+	push r11                        ; RFLAGS
+	push GDT_USER_CODE | 3          ; CS
+	push rcx                        ; RIP
+	push 0                          ; vector and error code (unused)
 	push rax
 	push rbx
-	push 0             ; RCX (modified by syscall)
+	push 0                          ; RCX (modified by syscall)
 	push rdx
 	push rsi
 	push rdi
@@ -41,26 +49,16 @@ syscall_handler:
 	push r8
 	push r9
 	push r10
-	push 0             ; R11 (modified by syscall)
+	push 0                          ; R11 (modified by syscall)
 	push r12
 	push r13
 	push r14
 	push r15
 
-	mov [rsp + 160], ss
-	mov [rsp + 136], cs
-
-	; ; Terrible improvised hack, this should be done through gs instead
-	mov rdi, sysc_saved_rsp
-	mov [rdi], rsp
 	mov rdi, rsp
-	mov rsp, sysc_stack_bot
 
 	call syscall_dispatch
 
-	mov rdi, sysc_saved_rsp
-	mov rsp, [rdi]
-
 	pop r15
 	pop r14
 	pop r13
@@ -81,8 +79,11 @@ syscall_handler:
 	add rsp, 8 ; CS
 	pop r11    ; RFLAGS
 	pop rsp    ; user RSP
-	add rsp, 8 ; SS
-	sub rsp, 8 ; adjust RSP
 
 	o64 sysret
 
+section .bss
+sysc_kernel_rsp:
+	resq 1
+sysc_user_rsp:
+	resq 1
@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, slice};
+
+/// Whether kernel is prevented from accessing user pages by SMAP, see `arch::init()`
+pub(super) static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Instruction which may fault while accessing user memory and where to continue if it does
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: u64,
+    fixup: u64,
+}
+
+extern "C" {
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// Find where to continue after a fault at `rip` in kernel mode, if it's recoverable
+pub fn find_fixup(rip: u64) -> Option<u64> {
+    let table = unsafe {
+        let start = &__ex_table_start as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const ExceptionTableEntry;
+        let len = (end as usize - start as usize) / mem::size_of::<ExceptionTableEntry>();
+
+        slice::from_raw_parts(start, len)
+    };
+
+    table.iter().find(|entry| entry.insn == rip).map(|entry| entry.fixup)
+}
+
+/// Temporarily allow the kernel to access user pages in `f`
+pub fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+
+    // Instructions are undefined without SMAP support
+    if smap {
+        unsafe {
+            asm!("stac", options(nostack));
+        }
+    }
+
+    let ret = f();
+
+    if smap {
+        unsafe {
+            asm!("clac", options(nostack));
+        }
+    }
+
+    ret
+}
+
+/// Copy `len` bytes from `src` to `dst`, either of which is in userspace. Returns number of bytes
+/// that were left uncopied because of a page fault.
+pub fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    with_user_access(|| raw_copy_user(dst, src, len))
+}
+
+/// Copy NUL-terminated string of at most `max` bytes from userspace `src` to `dst`. Returns length
+/// of the string without NUL, `max` if it wasn't terminated or -1 in case of a page fault.
+pub fn strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> isize {
+    with_user_access(|| raw_strncpy_user(dst, src, max))
+}
+
+#[naked]
+extern "C" fn raw_copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    unsafe {
+        asm!(
+            "mov rcx, rdx",
+            "2:",
+            "rep movsb",
+            "3:",
+            "mov rax, rcx",
+            "ret",
+            ".pushsection .ex_table, \"a\"",
+            ".quad 2b, 3b",
+            ".popsection",
+            options(noreturn)
+        );
+    }
+}
+
+#[naked]
+extern "C" fn raw_strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> isize {
+    unsafe {
+        asm!(
+            "xor rax, rax",
+            "2:",
+            "cmp rax, rdx",
+            "je 4f",
+            "3:",
+            "mov cl, [rsi + rax]",
+            "mov [rdi + rax], cl",
+            "test cl, cl",
+            "jz 4f",
+            "inc rax",
+            "jmp 2b",
+            "5:",
+            "mov rax, -1",
+            "4:",
+            "ret",
+            ".pushsection .ex_table, \"a\"",
+            ".quad 3b, 5b",
+            ".popsection",
+            options(noreturn)
+        );
+    }
+}
@@ -65,7 +65,7 @@ impl Serial for Uart {
         }
 
         // Enable receiver interrupts
-        // outb(COM1_PORT + COM_IER, COM_IER_RDI);
+        outb(COM1_PORT + COM_IER, COM_IER_RDI_BIT);
     }
 
     fn read_blocking(&self) -> u8 {
@@ -74,6 +74,10 @@ impl Serial for Uart {
         inb(COM1_PORT + COM_RBR)
     }
 
+    fn read_nonblocking(&self) -> Option<u8> {
+        self.can_read().then(|| inb(COM1_PORT + COM_RBR))
+    }
+
     fn write_blocking(&self, byte: u8) {
         while !self.can_write() {}
 
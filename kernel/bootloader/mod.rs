@@ -14,6 +14,7 @@ use crate::panic::panic_no_graphics;
 use crate::types::PowerOfTwoOps;
 
 const MMAP_MAX_ENTRIES: usize = 32;
+const MODULES_MAX_ENTRIES: usize = 8;
 
 trait Bootloader {
     fn get_info() -> BootloaderInfo;
@@ -23,6 +24,7 @@ pub struct BootloaderInfo {
     pub free_areas: MemoryMap,
     pub framebuffer: FramebufferInfo,
     pub section_headers: Option<SectionInfo>,
+    pub modules: Modules,
 }
 
 pub struct MemoryMap {
@@ -30,6 +32,13 @@ pub struct MemoryMap {
     pub num_entries: usize,
 }
 
+/// Physical memory of files loaded by the bootloader along with the kernel, e.g. initial ramdisk
+#[derive(Default)]
+pub struct Modules {
+    pub entries: [Region; MODULES_MAX_ENTRIES],
+    pub num_entries: usize,
+}
+
 #[derive(Default, Clone, Copy)]
 pub struct Region {
     pub start: usize,
@@ -259,6 +268,26 @@ impl MemoryMap {
     }
 }
 
+impl Modules {
+    pub fn iter(&self) -> impl Iterator<Item = &Region> + Clone {
+        self.entries[..self.num_entries].iter()
+    }
+
+    fn push(&mut self, region: Region) {
+        if self.num_entries >= MODULES_MAX_ENTRIES {
+            panic_no_graphics("Bootloader: module entry overflow");
+        }
+
+        self.entries[self.num_entries] = region;
+        self.num_entries += 1;
+    }
+
+    /// Address right after the last module, or 0 if there are none
+    pub fn end(&self) -> usize {
+        self.iter().map(|module| module.end).max().unwrap_or(0)
+    }
+}
+
 pub fn get_info() -> BootloaderInfo {
     multiboot::Multiboot::get_info()
 }
@@ -67,6 +67,7 @@ fn parse() -> BootloaderInfo {
     let mut mmap = None;
     let mut fb = None;
     let mut shdrs = None;
+    let mut modules = Modules::default();
 
     while total_size > 0 {
         let header = start as *const u32;
@@ -76,6 +77,7 @@ fn parse() -> BootloaderInfo {
 
         match tag_type {
             0 => break,
+            3 => modules.push(parse_module(header)),
             6 => mmap = Some(parse_mem_map(header)),
             8 => fb = Some(parse_framebuffer_info(header)),
             9 => shdrs = Some(parse_elf_sections(header)),
@@ -91,6 +93,7 @@ fn parse() -> BootloaderInfo {
         framebuffer: fb
             .unwrap_or_else(|| panic_no_graphics("Multiboot: framebuffer tag not found")),
         section_headers: shdrs,
+        modules,
     };
 
     remove_reserved_areas(&mut info);
@@ -98,6 +101,30 @@ fn parse() -> BootloaderInfo {
     info
 }
 
+fn parse_module(header: *const u32) -> Region {
+    /*        +-------------------+
+     * u32    | type = 3          |
+     * u32    | size              |
+     * u32    | mod_start         |
+     * u32    | mod_end           |
+     * varies | string            |
+     *        +-------------------+
+     *
+     * This tag indicates to the kernel what boot module was loaded along with the kernel image,
+     * and where it can be found. The `mod_start` and `mod_end` contain the start and end physical
+     * addresses of the boot module itself. The `string` field provides an arbitrary string to be
+     * associated with that particular boot module; it is a zero-terminated UTF-8 string, just like
+     * the kernel command line. One tag appears per module.
+     */
+
+    unsafe {
+        let start = header.offset(2).read() as usize;
+        let end = header.offset(3).read() as usize;
+
+        Region { start, end }
+    }
+}
+
 fn parse_mem_map(header: *const u32) -> MemoryMap {
     /*        +-------------------+
      * u32    | type = 6          |
@@ -307,6 +334,9 @@ fn remove_reserved_areas(info: &mut BootloaderInfo) {
             addr..addr + size
         });
 
+    let module_ranges = info.modules.iter().map(|module| module.start..module.end);
+
     mmap.remove_reserved(&[first_page, io_hole, fb_range]);
     mmap.remove_reserved(&shdr_ranges);
+    mmap.remove_reserved(&module_ranges);
 }
@@ -2,202 +2,346 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use core::fmt;
 use core::mem::size_of;
 
-use crate::arch::mmu;
-use crate::mm;
+use abi::MAX_ARGS;
+pub use elf_parser::{Elf, Elf64Shdr, ElfError};
+use elf_parser::{Elf64Word, Segment, TlsTemplate, PF_W, PF_X};
+
+use crate::arch::{self, mmu};
 use crate::mm::types::{Address, RegisterFrameOps, RootPageDirOps, VirtAddr};
-use crate::process::Process;
+use crate::mm::vma::{AddError, PopulateError, Vma};
+use crate::mm::{self, kstack, uaccess, OutOfMemory};
+use crate::process::{Process, ProgramArgs};
 use crate::types::PowerOfTwoOps;
 
-type Elf64Addr = u64;
-type Elf64Off = u64;
-type Elf64Half = u16;
-type Elf64Word = u32;
-type Elf64Xword = u64;
-
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct Elf64Ehdr {
-    pub e_ident: [u8; 16],
-    pub e_type: Elf64Half,
-    pub e_machine: Elf64Half,
-    pub e_version: Elf64Word,
-    pub e_entry: Elf64Addr,
-    pub e_phoff: Elf64Off,
-    pub e_shoff: Elf64Off,
-    pub e_flags: Elf64Word,
-    pub e_ehsize: Elf64Half,
-    pub e_phentsize: Elf64Half,
-    pub e_phnum: Elf64Half,
-    pub e_shentsize: Elf64Half,
-    pub e_shnum: Elf64Half,
-    pub e_shstrndx: Elf64Half,
-}
-
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct Elf64Phdr {
-    pub p_type: Elf64Word,
-    pub p_flags: Elf64Word,
-    pub p_offset: Elf64Off,
-    pub p_vaddr: Elf64Addr,
-    pub p_paddr: Elf64Addr,
-    pub p_filesz: Elf64Xword,
-    pub p_memsz: Elf64Xword,
-    pub p_align: Elf64Xword,
-}
-
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct Elf64Shdr {
-    pub sh_name: Elf64Word,
-    pub sh_type: Elf64Word,
-    pub sh_flags: Elf64Xword,
-    pub sh_addr: Elf64Addr,
-    pub sh_offset: Elf64Off,
-    pub sh_size: Elf64Xword,
-    pub sh_link: Elf64Word,
-    pub sh_info: Elf64Word,
-    pub sh_addralign: Elf64Xword,
-    pub sh_entsize: Elf64Xword,
-}
-
-const EI_CLASS: usize = 4;
-const EI_DATA: usize = 5;
-const EI_OSABI: usize = 7;
-const EI_NIDENT: usize = 16;
-
-const ELFCLASS64: u8 = 2;
-const ELFDATA2LSB: u8 = 1;
-const ELFOSABI_SYSV: u8 = 0;
-
-const ET_EXEC: Elf64Half = 2;
-const EM_X86_64: Elf64Half = 62;
-const EV_CURRENT: Elf64Word = 1;
-
-const PT_LOAD: Elf64Word = 1;
-const PF_X: Elf64Word = 0b001;
-const PF_W: Elf64Word = 0b010;
-const PF_R: Elf64Word = 0b100;
-
-macro_rules! read_int {
-    ($ty:ident, $in:expr) => {{
-        let (int_bytes, rest) = $in.split_at(size_of::<$ty>());
-        $in = rest;
-        $ty::from_le_bytes(int_bytes.try_into().unwrap())
-    }};
-}
-
-macro_rules! read_bytes {
-    ($num:expr, $in:expr) => {{
-        let (bytes, rest) = $in.split_at($num);
-        $in = rest;
-        bytes
-    }};
-}
-
-macro_rules! read_last {
-    ($ty:ident, $in:expr) => {{
-        let ret = read_int!($ty, $in);
-        _ = $in;
-        ret
-    }};
-}
-
-macro_rules! check_field {
-    ($var:expr, $expected:expr) => {{
-        assert!(
-            $var == $expected,
-            "bad {} ({}), expected {} ({})",
-            stringify!($var),
-            $var,
-            stringify!($expected),
-            $expected
-        )
-    }};
-}
-
-pub fn load(process: &mut Process, elf: &[u8]) {
-    assert!(elf.len() > size_of::<Elf64Ehdr>(), "bad header length");
-    assert!(&elf[0..4] == b"\x7fELF", "bad magic");
-
-    let mut input = elf;
-
-    let e_ident = read_bytes!(EI_NIDENT, input);
-
-    check_field!(e_ident[EI_CLASS], ELFCLASS64);
-    check_field!(e_ident[EI_DATA], ELFDATA2LSB);
-    check_field!(e_ident[EI_OSABI], ELFOSABI_SYSV);
-
-    let e_type = read_int!(Elf64Half, input);
-    let e_machine = read_int!(Elf64Half, input);
-    let e_version = read_int!(Elf64Word, input);
-    let e_entry = read_int!(Elf64Addr, input);
-    let e_phoff = read_int!(Elf64Off, input);
-    let _e_shoff = read_int!(Elf64Off, input);
-    let _e_flags = read_int!(Elf64Word, input);
-    let _e_ehsize = read_int!(Elf64Half, input);
-    let e_phentsize = read_int!(Elf64Half, input);
-    let e_phnum = read_int!(Elf64Half, input);
-    let _e_shentsize = read_int!(Elf64Half, input);
-    let _e_shnum = read_int!(Elf64Half, input);
-    let _e_shstrndx = read_last!(Elf64Half, input);
-
-    check_field!(e_type, ET_EXEC);
-    check_field!(e_machine, EM_X86_64);
-    check_field!(e_version, EV_CURRENT);
-    check_field!(e_phentsize, size_of::<Elf64Phdr>() as u16);
-
-    let mut phdrs = &elf[e_phoff as usize..];
-    for _ in 0..e_phnum {
-        load_program_header(process, &mut phdrs, elf);
-    }
-
-    process.registers.set_program_counter(e_entry as usize);
-}
-
-fn load_program_header(process: &mut Process, input: &mut &[u8], elf: &[u8]) {
-    let p_type = read_int!(Elf64Word, *input);
-    let p_flags = read_int!(Elf64Word, *input);
-    let p_offset = read_int!(Elf64Off, *input);
-    let p_vaddr = read_int!(Elf64Addr, *input);
-    let _p_paddr = read_int!(Elf64Addr, *input);
-    let p_filesz = read_int!(Elf64Xword, *input);
-    let p_memsz = read_int!(Elf64Xword, *input);
-    let _p_align = read_int!(Elf64Xword, *input);
-
-    if p_type != PT_LOAD {
-        return;
-    }
-
-    let vaddr = VirtAddr::from_u64(p_vaddr);
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Size of random bytes pointed to by `AT_RANDOM`
+const AT_RANDOM_SIZE: usize = 16;
+
+/// Auxiliary vector entries, including the terminating `AT_NULL`
+const AUXV_ENTRIES: usize = 5;
+
+/// Thread control block only holds a pointer to itself, as required by x86-64 TLS ABI
+const TCB_SIZE: usize = size_of::<u64>();
+
+/// argc, argv and envp with their terminating NULLs, and auxv
+const MAX_STACK_WORDS: usize = 1 + MAX_ARGS + 2 + AUXV_ENTRIES * 2;
+
+/// Reason a program couldn't be started
+#[derive(Debug)]
+pub enum LoadError {
+    BadImage(ElfError),
+    OutOfMemory,
+    /// Every kernel stack is taken by other processes
+    NoKernelStack,
+    /// Memory the loader fills isn't covered by the areas it added
+    NotMapped,
+    /// Stack or TLS overlaps segments of an executable that can't be moved
+    Overlaps,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::BadImage(err)
+    }
+}
+
+impl From<OutOfMemory> for LoadError {
+    fn from(_: OutOfMemory) -> Self {
+        LoadError::OutOfMemory
+    }
+}
+
+impl From<kstack::AllocError> for LoadError {
+    fn from(err: kstack::AllocError) -> Self {
+        match err {
+            kstack::AllocError::NoSlots => LoadError::NoKernelStack,
+            kstack::AllocError::OutOfMemory => LoadError::OutOfMemory,
+        }
+    }
+}
+
+impl From<AddError> for LoadError {
+    fn from(err: AddError) -> Self {
+        match err {
+            AddError::Overlaps => LoadError::Overlaps,
+            AddError::OutOfMemory => LoadError::OutOfMemory,
+        }
+    }
+}
+
+impl From<PopulateError> for LoadError {
+    fn from(err: PopulateError) -> Self {
+        match err {
+            PopulateError::NotMapped => LoadError::NotMapped,
+            PopulateError::OutOfMemory => LoadError::OutOfMemory,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadImage(err) => write!(f, "{err}"),
+            LoadError::OutOfMemory => write!(f, "{OutOfMemory}"),
+            LoadError::NoKernelStack => write!(f, "{}", kstack::AllocError::NoSlots),
+            LoadError::NotMapped => write!(f, "{}", PopulateError::NotMapped),
+            LoadError::Overlaps => write!(f, "{}", AddError::Overlaps),
+        }
+    }
+}
+
+/// Validate an executable before anything is torn down to make room for it
+pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+    let elf = Elf::parse(bytes)?;
+
+    let max_base = if elf.is_position_independent() {
+        arch::USER_PIE_BASE.0 + (arch::USER_ASLR_PAGES - 1) * mmu::PAGE_SIZE
+    } else {
+        0
+    };
+
+    elf.check_placement(max_base as u64, arch::USER_SPACE_END.0 as u64)?;
+
+    if let Some(tls) = elf.tls()
+        && !tls_layout(&tls).is_some_and(|(size, ..)| size <= arch::USER_TLS_MAX_SIZE)
+    {
+        return Err(ElfError::BadTls);
+    }
+
+    Ok(elf)
+}
+
+/// Map the program into the address space of `process`. On failure, the caller is left to release
+/// whatever was mapped.
+pub fn load(
+    process: &mut Process,
+    elf: &Elf<'static>,
+    args: &ProgramArgs,
+) -> Result<(), LoadError> {
+    // Position-independent executables are linked at 0 and can be moved anywhere
+    let base = if elf.is_position_independent() {
+        random_page(arch::USER_PIE_BASE, arch::USER_ASLR_PAGES).0 as u64
+    } else {
+        0
+    };
+
+    for segment in elf.segments() {
+        add_segment(process, &segment, base)?;
+    }
+
+    // Heap starts empty right after the highest segment
+    let image_end = elf.segments().map(|segment| {
+        let (aligned, full_size) = segment_range(&segment, base);
+
+        (aligned + full_size).page_round_up()
+    });
+
+    process.brk_start = image_end.max().unwrap_or(VirtAddr(0));
+    process.brk = process.brk_start;
+
+    relocate(process, elf, base)?;
+
+    // Pages with relocations stay writable until they are applied
+    for segment in elf.segments() {
+        let (aligned, full_size) = segment_range(&segment, base);
+        let perms = flags_to_permissions(segment.flags);
+
+        process.root_dir.change_range_perms(aligned, full_size, perms);
+    }
+
+    if let Some(tls) = elf.tls() {
+        setup_tls(process, &tls)?;
+    }
+
+    // Entry point is inside a segment, whose placement at the highest base was checked by `parse`
+    let entry = base.checked_add(elf.entry()).expect("elf: entry point out of address space");
+
+    let auxv = [
+        (AT_PHDR, elf.program_headers_addr().map_or(0, |addr| base + addr)),
+        (AT_ENTRY, entry),
+        (AT_PAGESZ, mmu::PAGE_SIZE as u64),
+    ];
+
+    build_stack(process, args, &auxv)?;
+
+    process.registers.set_program_counter(entry as usize);
+
+    Ok(())
+}
+
+/// Pages covered by a segment loaded at `base`
+fn segment_range(segment: &Segment, base: u64) -> (VirtAddr, usize) {
+    let vaddr = VirtAddr::from_u64(base + segment.vaddr);
     let aligned = vaddr.page_round_down();
     let offset = vaddr.0 - aligned.0;
 
-    let size_in_mem = p_memsz as usize;
-    let full_size = size_in_mem + offset;
+    (aligned, segment.memsz as usize + offset)
+}
+
+/// Add an area for a loadable segment. Its pages are read from the file when first touched.
+fn add_segment(
+    process: &mut Process,
+    segment: &Segment<'static>,
+    base: u64,
+) -> Result<(), LoadError> {
+    let (aligned, full_size) = segment_range(segment, base);
+    let offset = full_size - segment.memsz as usize;
+    let perms = flags_to_permissions(segment.flags);
+
+    process.vmas.add(Vma::file(aligned, full_size, perms, segment.data, offset))?;
+
+    Ok(())
+}
+
+/// Static-PIE executables only need their own addresses adjusted by the load base, which is what
+/// their relocations do
+fn relocate(process: &mut Process, elf: &Elf, base: u64) -> Result<(), LoadError> {
+    for relocation in elf.relocations() {
+        let addr = VirtAddr::from_u64(base + relocation.offset);
+
+        process.vmas.populate(&mut process.root_dir, addr, size_of::<u64>(), mmu::WRITABLE)?;
+    }
+
+    process.root_dir.switch_to_this();
+
+    for relocation in elf.relocations() {
+        let ptr = (base + relocation.offset) as *mut u64;
+        let val = base.wrapping_add_signed(relocation.addend);
+
+        uaccess::with_user_access(|| unsafe { ptr.write_unaligned(val) });
+    }
+
+    mm::switch_to_kernel_root_dir();
+
+    Ok(())
+}
+
+/// Size of memory for TLS block and TCB, and offsets of both in it, unless they overflow
+fn tls_layout(tls: &TlsTemplate) -> Option<(usize, usize, usize)> {
+    let memsz = usize::try_from(tls.memsz).ok()?;
+    let tls_align = usize::try_from(tls.align).ok()?;
+    let tcb_offset = memsz.checked_next_multiple_of(tls_align.max(TCB_SIZE))?;
+    let block_offset = tcb_offset.checked_sub(memsz.checked_next_multiple_of(tls_align)?)?;
+
+    Some((tcb_offset.checked_add(TCB_SIZE)?, block_offset, tcb_offset))
+}
+
+/// Create TLS with variant II layout: TLS block immediately precedes TCB, which thread pointer
+/// points to. Variables are accessed at negative offsets from it.
+fn setup_tls(process: &mut Process, tls: &TlsTemplate) -> Result<(), LoadError> {
+    let (size, block_offset, tcb_offset) = tls_layout(tls).expect("elf: TLS layout was checked");
+    let start = random_page(arch::USER_TLS_START, arch::USER_ASLR_PAGES);
+    let tcb = start + tcb_offset;
 
-    let slice = unsafe { aligned.into_slice_mut(full_size) };
+    let perms = mmu::WRITABLE | mmu::USER_ACCESSIBLE | mmu::NON_EXECUTABLE;
 
-    let file_pos = p_offset as usize;
-    let file_len = p_filesz as usize;
+    process.vmas.add(Vma::anonymous(start, size, perms))?;
+    process.vmas.populate(&mut process.root_dir, start, size, 0)?;
 
-    process.root_dir.alloc_range(aligned, full_size, mmu::USER_ACCESSIBLE | mmu::WRITABLE);
+    process.root_dir.switch_to_this();
+
+    uaccess::with_user_access(|| {
+        let memory = unsafe { start.into_slice_mut(size) };
+        let block = &mut memory[block_offset..tcb_offset];
+
+        block.fill(0);
+        block[..tls.data.len()].copy_from_slice(tls.data);
+
+        memory[tcb_offset..].copy_from_slice(&(tcb.0 as u64).to_ne_bytes());
+    });
+
+    mm::switch_to_kernel_root_dir();
+
+    process.thread_pointer = tcb;
+
+    Ok(())
+}
+
+/// Pick a random page-aligned address among `pages` pages starting at `start`
+fn random_page(start: VirtAddr, pages: usize) -> VirtAddr {
+    let page = arch::random_u64() as usize % pages;
+
+    start + page * mmu::PAGE_SIZE
+}
+
+/// Lay out the System V initial stack: argc at the stack pointer, followed by argv and envp arrays
+/// terminated by NULL, and auxv terminated by `AT_NULL`. Strings and random bytes referenced by
+/// them are at the top of the stack.
+fn build_stack(
+    process: &mut Process,
+    args: &ProgramArgs,
+    auxv: &[(u64, u64)],
+) -> Result<(), LoadError> {
+    let limit = random_page(arch::USER_STACK_START + mmu::PAGE_SIZE, arch::USER_ASLR_PAGES);
+    let top = limit.0 + arch::USER_STACK_MAX_SIZE;
+    let random_addr = top - AT_RANDOM_SIZE;
+    let strings = args.strings();
+    let strings_addr = random_addr - strings.len();
+
+    let mut words = [0; MAX_STACK_WORDS];
+    let mut len = 0;
+    let mut push = |word| {
+        words[len] = word;
+        len += 1;
+    };
+
+    let mut addrs = strings.split_inclusive(|&ch| ch == 0).scan(strings_addr, |addr, string| {
+        let string_addr = *addr;
+        *addr += string.len();
+        Some(string_addr as u64)
+    });
+
+    push(args.argc() as u64);
+    addrs.by_ref().take(args.argc()).for_each(&mut push);
+    push(0);
+    addrs.for_each(&mut push);
+    push(0);
+
+    for &(key, val) in auxv.iter().chain(&[(AT_RANDOM, random_addr as u64), (AT_NULL, 0)]) {
+        push(key);
+        push(val);
+    }
+
+    // Stack pointer has to be 16-byte aligned at the entry point
+    let sp = (strings_addr - len * size_of::<u64>()).po2_round_down(16);
+
+    let mut random = [0; AT_RANDOM_SIZE];
+
+    for chunk in random.chunks_mut(size_of::<u64>()) {
+        chunk.copy_from_slice(&arch::random_u64().to_ne_bytes());
+    }
+
+    let stack = Vma::stack(VirtAddr(top), arch::USER_STACK_SIZE, arch::USER_STACK_MAX_SIZE);
+
+    process.vmas.add(stack)?;
+    process.vmas.populate(&mut process.root_dir, VirtAddr(sp), top - sp, 0)?;
 
     process.root_dir.switch_to_this();
 
-    slice.fill(0);
+    uaccess::with_user_access(|| {
+        let stack = unsafe { VirtAddr(sp).into_slice_mut(top - sp) };
 
-    let smaller_size = usize::min(size_in_mem, file_len);
-    let virt = &mut slice[offset..offset + smaller_size];
-    let file = &elf[file_pos..file_pos + smaller_size];
+        for (dst, word) in stack.chunks_mut(size_of::<u64>()).zip(&words[..len]) {
+            dst.copy_from_slice(&word.to_ne_bytes());
+        }
 
-    virt.copy_from_slice(file);
+        stack[strings_addr - sp..random_addr - sp].copy_from_slice(strings);
+        stack[random_addr - sp..].copy_from_slice(&random);
+    });
 
     mm::switch_to_kernel_root_dir();
 
-    process.root_dir.change_range_perms(aligned, full_size, flags_to_permissions(p_flags));
+    process.registers.set_stack_pointer(sp);
+
+    Ok(())
 }
 
 fn flags_to_permissions(p_flags: Elf64Word) -> usize {
@@ -205,8 +349,6 @@ fn flags_to_permissions(p_flags: Elf64Word) -> usize {
 
     if p_flags & PF_W != 0 {
         perms |= mmu::WRITABLE;
-
-        assert!(p_flags & PF_X == 0, "elf: writable and executable regions not allowed");
     }
 
     if p_flags & PF_X == 0 {
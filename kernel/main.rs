@@ -19,6 +19,8 @@
 #![feature(panic_info_message)]
 #![feature(try_trait_v2)]
 
+extern crate alloc;
+
 #[macro_use]
 mod printk;
 
@@ -32,11 +34,15 @@ mod elf;
 mod mm;
 mod panic;
 mod process;
+mod programs;
+mod ring_buffer;
 mod sched;
 mod serial;
 mod small_vec;
 mod spinlock;
 mod syscalls;
+mod time;
+mod timer;
 mod types;
 
 #[no_mangle]
@@ -48,18 +54,24 @@ pub extern "C" fn kmain() {
     let mut info = bootloader::get_info();
     mm::init(&mut info);
     console::init(&info);
+    programs::init(&info);
 
     arch::init();
 
     arch::interrupts::init();
 
+    println!("Current time: {} UTC", arch::interrupts::rtc::read_time());
+
     println!("Available memory:");
     print!("{}", &info.free_areas);
 
+    println!("Free page blocks:");
+    print!("{}", mm::pg_alloc::stats());
+
     println!("Kernel sections:");
     print!("{}", info.section_headers.as_ref().unwrap());
 
-    sched::init(&info);
+    sched::init();
 
     arch::interrupts::enable();
 
@@ -0,0 +1,298 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+use super::ROOT_KERN_DIR;
+use crate::arch::{self, mmu};
+use crate::mm::pg_alloc;
+use crate::mm::types::{PhysAddr, RootPageDirOps, VirtAddr};
+use crate::spinlock::Mutex;
+use crate::types::PowerOfTwoOps;
+
+/// Object sizes of slab caches. Anything bigger is given whole pages.
+const SLAB_SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// Number of pages in the region of virtual memory where allocations of several pages are mapped
+const LARGE_PAGES: usize = 1 << 16;
+
+static CACHES: Mutex<[SlabCache; SLAB_SIZES.len()]> =
+    Mutex::new([SlabCache::new(); SLAB_SIZES.len()]);
+static LARGE_USED: Mutex<[u64; LARGE_PAGES / 64]> = Mutex::new([0; LARGE_PAGES / 64]);
+
+#[global_allocator]
+static HEAP: Heap = Heap;
+
+/// Kernel heap. Small objects are carved out of pages by slab caches of fixed sizes, single pages
+/// are taken from the direct mapping of physical memory, and bigger allocations are assembled from
+/// separate pages mapped contiguously in a dedicated region.
+struct Heap;
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Kind::of(layout) {
+            Kind::Slab(idx) => CACHES.lock()[idx].alloc(SLAB_SIZES[idx]),
+            Kind::Page => alloc_page(),
+            Kind::Large(pages) => alloc_large(pages),
+            Kind::Unsupported => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Kind::of(layout) {
+            Kind::Slab(idx) => CACHES.lock()[idx].dealloc(ptr),
+            Kind::Page => free_page(VirtAddr(ptr as usize)),
+            Kind::Large(pages) => free_large(VirtAddr(ptr as usize), pages),
+            Kind::Unsupported => unreachable!(),
+        }
+    }
+}
+
+enum Kind {
+    Slab(usize),
+    Page,
+    Large(usize),
+    Unsupported,
+}
+
+impl Kind {
+    fn of(layout: Layout) -> Self {
+        let size = layout.size().max(layout.align());
+
+        if layout.align() > mmu::PAGE_SIZE {
+            Kind::Unsupported
+        } else if let Some(idx) = SLAB_SIZES.iter().position(|&slab_size| size <= slab_size) {
+            Kind::Slab(idx)
+        } else if size <= mmu::PAGE_SIZE {
+            Kind::Page
+        } else {
+            Kind::Large(size.div_ceil(mmu::PAGE_SIZE))
+        }
+    }
+}
+
+/// Header at the start of every slab page, followed by objects
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    used: usize,
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Slabs of objects of one size that have free objects in them. Full slabs are not tracked until an
+/// object in them is freed.
+#[derive(Clone, Copy)]
+struct SlabCache {
+    partial: Option<NonNull<Slab>>,
+}
+
+impl Slab {
+    fn create(object_size: usize) -> Option<NonNull<Slab>> {
+        let addr = alloc_page();
+
+        if addr.is_null() {
+            return None;
+        }
+        let first = size_of::<Slab>().next_multiple_of(object_size);
+        let mut free = None;
+
+        for offset in (first..mmu::PAGE_SIZE).step_by(object_size).rev() {
+            let object = unsafe { addr.add(offset).cast::<FreeObject>() };
+
+            unsafe { object.write(FreeObject { next: free }) };
+
+            free = NonNull::new(object);
+        }
+
+        let slab = addr.cast::<Slab>();
+
+        unsafe {
+            slab.write(Slab {
+                prev: None,
+                next: None,
+                free,
+                used: 0,
+            });
+
+            Some(NonNull::new_unchecked(slab))
+        }
+    }
+
+    /// Slab that an object belongs to
+    fn of(ptr: *mut u8) -> NonNull<Slab> {
+        let addr = (ptr as usize).page_round_down();
+
+        unsafe { NonNull::new_unchecked(addr as *mut Slab) }
+    }
+}
+
+impl SlabCache {
+    const fn new() -> Self {
+        SlabCache { partial: None }
+    }
+
+    fn alloc(&mut self, object_size: usize) -> *mut u8 {
+        let mut slab_ptr = match self.partial {
+            Some(slab) => slab,
+            None => {
+                let Some(slab) = Slab::create(object_size) else {
+                    return ptr::null_mut();
+                };
+
+                self.push(slab);
+                slab
+            }
+        };
+        let slab = unsafe { slab_ptr.as_mut() };
+        let object = slab.free.unwrap();
+
+        slab.free = unsafe { object.as_ref().next };
+        slab.used += 1;
+
+        if slab.free.is_none() {
+            self.unlink(slab_ptr);
+        }
+
+        object.as_ptr().cast()
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8) {
+        let mut slab_ptr = Slab::of(ptr);
+        let slab = unsafe { slab_ptr.as_mut() };
+        let was_full = slab.free.is_none();
+        let object = ptr.cast::<FreeObject>();
+
+        unsafe { object.write(FreeObject { next: slab.free }) };
+
+        slab.free = NonNull::new(object);
+        slab.used -= 1;
+
+        if was_full {
+            self.push(slab_ptr);
+        }
+
+        // Keep the last partial slab around to not bounce a page on every alloc/free pair
+        if slab.used == 0 && (slab.prev.is_some() || slab.next.is_some()) {
+            self.unlink(slab_ptr);
+
+            free_page(VirtAddr(slab_ptr.as_ptr() as usize));
+        }
+    }
+
+    fn push(&mut self, mut slab_ptr: NonNull<Slab>) {
+        let slab = unsafe { slab_ptr.as_mut() };
+
+        slab.prev = None;
+        slab.next = self.partial;
+
+        if let Some(mut head) = self.partial {
+            unsafe { head.as_mut().prev = Some(slab_ptr) };
+        }
+
+        self.partial = Some(slab_ptr);
+    }
+
+    fn unlink(&mut self, mut slab_ptr: NonNull<Slab>) {
+        let slab = unsafe { slab_ptr.as_mut() };
+
+        match slab.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = slab.next },
+            None => self.partial = slab.next,
+        }
+
+        if let Some(mut next) = slab.next {
+            unsafe { next.as_mut().prev = slab.prev };
+        }
+
+        slab.prev = None;
+        slab.next = None;
+    }
+}
+
+/// Allocations can fail, so running out of memory returns null instead of panicking
+fn alloc_page() -> *mut u8 {
+    let Ok(page) = pg_alloc::alloc_page() else {
+        return ptr::null_mut();
+    };
+
+    page.inc_refc().to_physaddr().into_vaddr().0 as *mut u8
+}
+
+fn free_page(addr: VirtAddr) {
+    pg_alloc::perform_page_op(PhysAddr::from(addr), |page| {
+        page.dec_refc();
+    });
+}
+
+/// Find a free run of `pages` pages in the large allocation region, followed by an unmapped guard
+/// page, and map it
+fn alloc_large(pages: usize) -> *mut u8 {
+    let slots = pages + 1;
+    let start = {
+        let mut used = LARGE_USED.lock();
+        let is_used = |slot: usize| used[slot / 64] & (1 << (slot % 64)) != 0;
+        let mut start = 0;
+        let mut run = 0;
+
+        for slot in 0..LARGE_PAGES {
+            if is_used(slot) {
+                run = 0;
+                start = slot + 1;
+            } else {
+                run += 1;
+            }
+
+            if run == slots {
+                break;
+            }
+        }
+
+        if run < slots {
+            return ptr::null_mut();
+        }
+
+        for slot in start..start + slots {
+            used[slot / 64] |= 1 << (slot % 64);
+        }
+
+        start
+    };
+
+    let addr = arch::KERNEL_HEAP_BASE + start * mmu::PAGE_SIZE;
+    let perms = mmu::WRITABLE | mmu::NON_EXECUTABLE;
+
+    if ROOT_KERN_DIR.lock().alloc_range(addr, pages * mmu::PAGE_SIZE, perms).is_err() {
+        release_slots(start, slots);
+
+        return ptr::null_mut();
+    }
+
+    addr.0 as *mut u8
+}
+
+fn free_large(addr: VirtAddr, pages: usize) {
+    let mut root_dir = ROOT_KERN_DIR.lock();
+
+    for page in 0..pages {
+        root_dir.unmap_page_at_addr(addr + page * mmu::PAGE_SIZE);
+    }
+
+    drop(root_dir);
+
+    release_slots((addr.0 - arch::KERNEL_HEAP_BASE.0) / mmu::PAGE_SIZE, pages + 1);
+}
+
+fn release_slots(start: usize, slots: usize) {
+    let mut used = LARGE_USED.lock();
+
+    for slot in start..start + slots {
+        used[slot / 64] &= !(1 << (slot % 64));
+    }
+}
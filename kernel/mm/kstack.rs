@@ -0,0 +1,98 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::fmt;
+
+use super::ROOT_KERN_DIR;
+use crate::arch::{self, mmu};
+use crate::mm::types::{RootPageDirOps, VirtAddr};
+use crate::mm::OutOfMemory;
+use crate::spinlock::Mutex;
+
+const STACK_PAGES: usize = 4;
+const STACK_SIZE: usize = STACK_PAGES * mmu::PAGE_SIZE;
+const MAX_STACKS: usize = 256;
+
+/// Each stack is preceded by an unmapped guard page
+const SLOT_SIZE: usize = STACK_SIZE + mmu::PAGE_SIZE;
+
+static USED_SLOTS: Mutex<[bool; MAX_STACKS]> = Mutex::new([false; MAX_STACKS]);
+
+/// Reason a kernel stack couldn't be allocated
+#[derive(Debug)]
+pub enum AllocError {
+    /// Every slot of the kernel stack region is taken
+    NoSlots,
+    OutOfMemory,
+}
+
+/// Stack used by a process while it's executing in the kernel. Lives in a region of virtual memory
+/// that is shared between all address spaces, so that a process can be suspended in the middle of a
+/// syscall and resumed later from any other one.
+#[derive(Clone, Copy)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    pub fn alloc() -> Result<Self, AllocError> {
+        let slot = {
+            let mut used = USED_SLOTS.lock();
+            let slot = used.iter().position(|&used| !used).ok_or(AllocError::NoSlots)?;
+            used[slot] = true;
+            slot
+        };
+
+        let stack = KernelStack { slot };
+        let perms = mmu::WRITABLE | mmu::NON_EXECUTABLE;
+
+        if let Err(err) = ROOT_KERN_DIR.lock().alloc_range(stack.bottom(), STACK_SIZE, perms) {
+            USED_SLOTS.lock()[slot] = false;
+
+            return Err(err.into());
+        }
+
+        Ok(stack)
+    }
+
+    pub fn free(self) {
+        let mut root_dir = ROOT_KERN_DIR.lock();
+
+        for page in 0..STACK_PAGES {
+            root_dir.unmap_page_at_addr(self.bottom() + page * mmu::PAGE_SIZE);
+        }
+
+        USED_SLOTS.lock()[self.slot] = false;
+    }
+
+    fn bottom(&self) -> VirtAddr {
+        arch::KERNEL_STACKS_BASE + self.slot * SLOT_SIZE + mmu::PAGE_SIZE
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+impl From<OutOfMemory> for AllocError {
+    fn from(_: OutOfMemory) -> Self {
+        AllocError::OutOfMemory
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::NoSlots => write!(f, "out of kernel stack slots"),
+            AllocError::OutOfMemory => write!(f, "{OutOfMemory}"),
+        }
+    }
+}
+
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let base = arch::KERNEL_STACKS_BASE.0;
+    let end = base + MAX_STACKS * SLOT_SIZE;
+
+    (base..end).contains(&addr.0) && (addr.0 - base) % SLOT_SIZE < mmu::PAGE_SIZE
+}
@@ -2,8 +2,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+mod heap;
+pub mod kstack;
 pub mod pg_alloc;
 pub mod types;
+pub mod uaccess;
+pub mod vma;
+
+use core::fmt;
 
 use self::types::{PhysAddr, VirtAddr};
 use crate::arch::{self, mmu, RootPageDir};
@@ -16,12 +22,26 @@ extern "C" {
     fn stack_guard_top();
     fn stack_guard_bot();
     fn int_stack_guard_bot();
-    fn priv_stack_guard_bot();
-    fn sysc_stack_guard_bot();
+
+    fn __stext();
+    fn __etext();
+    fn __srodata();
+    fn __erodata();
+    fn __ebss();
 }
 
 static ROOT_KERN_DIR: Mutex<RootPageDir> = Mutex::new(arch::EMPTY_ROOT_DIR);
 
+/// Physical memory ran out while allocating a page
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "out of memory")
+    }
+}
+
 pub fn init(info: &mut BootloaderInfo) {
     let (maxpages, pg_alloc_start, pg_alloc_size) = pg_alloc::get_pg_alloc_region(info);
 
@@ -33,8 +53,8 @@ pub fn init(info: &mut BootloaderInfo) {
 }
 
 fn create_kern_root_dir(maxpages: usize) -> RootPageDir {
-    let mut root_dir = RootPageDir::new();
-    let phys_flags = mmu::PRESENT | mmu::WRITABLE;
+    let mut root_dir = RootPageDir::new().expect("Out of memory for kernel page directory");
+    let phys_flags = mmu::PRESENT | mmu::WRITABLE | mmu::NON_EXECUTABLE;
 
     println_serial!("Mapping physical memory...");
 
@@ -45,33 +65,53 @@ fn create_kern_root_dir(maxpages: usize) -> RootPageDir {
     // Bochs hack where we can't have more than 2 GiB of RAM but its puts framebuffer at 3.5 GiB
     root_dir.map_region_large(VirtAddr(0xffffff80e0000000), PhysAddr(0xe0000000), 64, phys_flags);
 
+    println_serial!("Mapping kernel sections...");
+
+    map_kernel_sections(root_dir, phys_flags);
+
     println_serial!("Mapping stack guards...");
 
-    unmap_guard_page(root_dir, stack_guard_top as usize, phys_flags);
-    unmap_guard_page(root_dir, stack_guard_bot as usize, phys_flags);
-    unmap_guard_page(root_dir, int_stack_guard_bot as usize, phys_flags);
-    unmap_guard_page(root_dir, priv_stack_guard_bot as usize, phys_flags);
-    unmap_guard_page(root_dir, sysc_stack_guard_bot as usize, phys_flags);
+    // Stacks are in .bss, which is already mapped with regular pages
+    root_dir.unmap_region(VirtAddr(stack_guard_top as usize), 1);
+    root_dir.unmap_region(VirtAddr(stack_guard_bot as usize), 1);
+    root_dir.unmap_region(VirtAddr(int_stack_guard_bot as usize), 1);
+
+    // Kernel stacks and heap are shared with every address space through these top-level entries,
+    // so they have to exist before any process is created
+    root_dir.walk_dir(arch::KERNEL_STACKS_BASE, true).unwrap();
+    root_dir.walk_dir(arch::KERNEL_HEAP_BASE, true).unwrap();
 
     root_dir.switch_to_this();
 
     root_dir
 }
 
-fn unmap_guard_page(mut root_dir: RootPageDir, addr: usize, phys_flags: usize) {
-    let vaddr = VirtAddr(addr);
-    let large = vaddr.lpage_round_down();
+/// Make text read-only and executable and rodata read-only. Everything else stays writable and
+/// non-executable, as it is in the rest of physical memory mapping.
+fn map_kernel_sections(mut root_dir: RootPageDir, phys_flags: usize) {
+    let start = VirtAddr(__stext as usize).lpage_round_down();
+    let end = VirtAddr(__ebss as usize).lpage_round_up();
+    let lpages = (end.0 - start.0) / mmu::PAGE_SIZE_LARGE;
 
-    // Memory on guard pages is covered by large-page mapping of all phys memory. Unmap it first.
-    root_dir.unmap_region_large(large, 1);
+    // Kernel image is covered by large-page mapping of all phys memory. Unmap it first.
+    root_dir.unmap_region_large(start, lpages);
 
     // Recreate the mapping but with lower granularity
-    root_dir.map_region(large, large.into(), mmu::PAGE_SIZE_LARGE / mmu::PAGE_SIZE, phys_flags);
+    root_dir.map_region(start, start.into(), (end.0 - start.0) / mmu::PAGE_SIZE, phys_flags);
+
+    let text = VirtAddr(__stext as usize);
+    let text_size = (__etext as usize).page_round_up() - text.0;
+    root_dir.change_range_perms(text, text_size, mmu::PRESENT);
 
-    // Finally, unmap guard page
-    root_dir.unmap_region(vaddr, 1);
+    let rodata = VirtAddr(__srodata as usize);
+    let rodata_size = (__erodata as usize).page_round_up() - rodata.0;
+    root_dir.change_range_perms(rodata, rodata_size, mmu::PRESENT | mmu::NON_EXECUTABLE);
 }
 
 pub fn switch_to_kernel_root_dir() {
     ROOT_KERN_DIR.lock().switch_to_this();
 }
+
+pub fn kernel_root_dir() -> RootPageDir {
+    *ROOT_KERN_DIR.lock()
+}
@@ -2,50 +2,66 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use core::fmt;
 use core::mem::size_of;
-use core::ptr::{addr_of, addr_of_mut, NonNull};
+use core::ptr::{self, addr_of, NonNull};
 
 use crate::arch::{mmu, KERNEL_BASE};
 use crate::bootloader::{BootloaderInfo, Region, SectionInfoIterator};
 use crate::mm::types::{PhysAddr, VirtAddr};
+use crate::mm::OutOfMemory;
 use crate::spinlock::Mutex;
 use crate::types::PowerOfTwoOps;
 
+/// Largest block is 2^MAX_ORDER pages, enough for a large page
+pub const MAX_ORDER: usize = 10;
+
 static PAGE_INFOS: Mutex<&mut [PageInfo]> = Mutex::new(&mut []);
-static FREE_PAGES: Mutex<Option<NonNull<PageInfo>>> = Mutex::new(None);
+static FREE_AREAS: Mutex<FreeAreas> = Mutex::new(FreeAreas::empty());
 
 #[derive(Default)]
 pub struct PageInfo {
     next: Option<NonNull<PageInfo>>,
-    refc: u16,
+    prev: Option<NonNull<PageInfo>>,
+    refc: u32,
+    /// Block headed by this page is 2^order pages long
+    order: u8,
+    /// Page heads a block in one of the free lists
+    free: bool,
+}
+
+/// Buddy allocator. Free blocks of each order are kept in separate lists, and a block of order N is
+/// aligned to 2^N pages, so its buddy, with which it forms a block of the next order, is found by
+/// flipping a bit in the page index.
+struct FreeAreas {
+    infos: *mut PageInfo,
+    maxpages: usize,
+    lists: [Option<NonNull<PageInfo>>; MAX_ORDER + 1],
+    counts: [usize; MAX_ORDER + 1],
+}
+
+/// Number of free blocks of every order
+pub struct Stats {
+    pub free_blocks: [usize; MAX_ORDER + 1],
 }
 
 impl PageInfo {
-    fn alloc() -> Option<&'static mut PageInfo> {
-        let mut freep = FREE_PAGES.lock();
-        let mut head = (*freep)?;
-        let pgref = unsafe { head.as_mut() };
+    fn alloc(order: usize) -> Option<&'static mut PageInfo> {
+        let pgref = FREE_AREAS.lock().alloc(order)?;
 
         unsafe {
             let vaddr = pgref.to_physaddr().into_vaddr();
-            let region = vaddr.into_slice_mut(mmu::PAGE_SIZE);
+            let region = vaddr.into_slice_mut(mmu::PAGE_SIZE << order);
             region.fill(0);
         }
 
-        *freep = pgref.next;
-
-        pgref.next = None;
-
         Some(pgref)
     }
 
     fn free(&mut self) {
         assert!(self.refc == 0, "free_page: page is used");
-        let mut freep = FREE_PAGES.lock();
-
-        self.next = *freep;
 
-        *freep = unsafe { Some(NonNull::new_unchecked(self as *mut _)) };
+        FREE_AREAS.lock().free(self);
     }
 
     pub fn to_physaddr(&self) -> PhysAddr {
@@ -59,6 +75,11 @@ impl PageInfo {
         PhysAddr(addr)
     }
 
+    /// Number of users of this page, e.g. address spaces sharing it after fork
+    pub fn refc(&self) -> u32 {
+        self.refc
+    }
+
     pub fn inc_refc(&mut self) -> &mut Self {
         self.refc += 1;
         self
@@ -73,6 +94,153 @@ impl PageInfo {
     }
 }
 
+impl FreeAreas {
+    const fn empty() -> Self {
+        FreeAreas {
+            infos: ptr::null_mut(),
+            maxpages: 0,
+            lists: [None; MAX_ORDER + 1],
+            counts: [0; MAX_ORDER + 1],
+        }
+    }
+
+    fn page(&self, idx: usize) -> &'static mut PageInfo {
+        assert!(idx < self.maxpages);
+
+        unsafe { &mut *self.infos.add(idx) }
+    }
+
+    fn index_of(&self, page: &PageInfo) -> usize {
+        (addr_of!(*page) as usize - self.infos as usize) / size_of::<PageInfo>()
+    }
+
+    fn alloc(&mut self, order: usize) -> Option<&'static mut PageInfo> {
+        let found = (order..=MAX_ORDER).find(|&o| self.lists[o].is_some())?;
+        let idx = self.pop(found);
+
+        // Split the block, returning upper halves to the free lists
+        for lower in (order..found).rev() {
+            self.push(idx + (1 << lower), lower);
+        }
+
+        let page = self.page(idx);
+
+        page.order = order as u8;
+
+        Some(page)
+    }
+
+    fn free(&mut self, page: &PageInfo) {
+        let mut idx = self.index_of(page);
+        let mut order = page.order as usize;
+
+        while order < MAX_ORDER {
+            let buddy_idx = idx ^ (1 << order);
+
+            if buddy_idx >= self.maxpages {
+                break;
+            }
+
+            let buddy = self.page(buddy_idx);
+
+            if !buddy.free || buddy.order as usize != order {
+                break;
+            }
+
+            self.remove(buddy_idx);
+
+            idx = idx.min(buddy_idx);
+            order += 1;
+        }
+
+        self.push(idx, order);
+    }
+
+    fn push(&mut self, idx: usize, order: usize) {
+        let page = self.page(idx);
+
+        page.next = self.lists[order];
+        page.prev = None;
+        page.order = order as u8;
+        page.free = true;
+
+        if let Some(mut head) = self.lists[order] {
+            unsafe { head.as_mut().prev = Some(NonNull::from(&*page)) };
+        }
+
+        self.lists[order] = Some(NonNull::from(page));
+        self.counts[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> usize {
+        let head = self.lists[order].unwrap();
+        let idx = self.index_of(unsafe { head.as_ref() });
+
+        self.remove(idx);
+
+        idx
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let page = self.page(idx);
+        let order = page.order as usize;
+
+        match page.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = page.next },
+            None => self.lists[order] = page.next,
+        }
+
+        if let Some(mut next) = page.next {
+            unsafe { next.as_mut().prev = page.prev };
+        }
+
+        page.next = None;
+        page.prev = None;
+        page.free = false;
+
+        self.counts[order] -= 1;
+    }
+}
+
+impl Stats {
+    pub fn free_pages(&self) -> usize {
+        self.free_blocks.iter().enumerate().map(|(order, count)| count << order).sum()
+    }
+
+    /// Percentage of free memory that can't be used for a block of given order, because it's split
+    /// into smaller blocks
+    pub fn fragmentation(&self, order: usize) -> usize {
+        let free = self.free_pages();
+        let unusable: usize =
+            self.free_blocks[..order].iter().enumerate().map(|(o, c)| c << o).sum();
+
+        if free == 0 {
+            return 0;
+        }
+
+        unusable * 100 / free
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (order, count) in self.free_blocks.iter().enumerate() {
+            writeln!(f, "order {:2}: {} free", order, count)?;
+        }
+
+        writeln!(
+            f,
+            "free pages={}, fragmentation for large pages={}%",
+            self.free_pages(),
+            self.fragmentation(
+                mmu::PAGE_SIZE_LARGE.ilog2() as usize - mmu::PAGE_SIZE.ilog2() as usize
+            )
+        )?;
+
+        Ok(())
+    }
+}
+
 pub fn perform_page_op(addr: PhysAddr, mut op: impl FnMut(&mut PageInfo)) {
     let idx = addr.0 / mmu::PAGE_SIZE;
     let page = &mut PAGE_INFOS.lock()[idx];
@@ -82,12 +250,14 @@ pub fn perform_page_op(addr: PhysAddr, mut op: impl FnMut(&mut PageInfo)) {
 
 pub fn init(area_start: VirtAddr, maxpages: usize, info: &mut BootloaderInfo) {
     let mut infos = PAGE_INFOS.lock();
-    let mut freep = FREE_PAGES.lock();
+    let mut areas = FREE_AREAS.lock();
 
     *infos = unsafe { core::slice::from_raw_parts_mut(area_start.0 as *mut PageInfo, maxpages) };
     infos.fill_with(Default::default); // mark all as non-free
 
-    *freep = None;
+    *areas = FreeAreas::empty();
+    areas.infos = infos.as_mut_ptr();
+    areas.maxpages = maxpages;
 
     println_serial!(
         "Initializing page information list at {:#x}..{:#x}...",
@@ -96,7 +266,7 @@ pub fn init(area_start: VirtAddr, maxpages: usize, info: &mut BootloaderInfo) {
     );
 
     let mmap = &info.free_areas;
-    for eidx in (0..mmap.num_entries).rev() {
+    for eidx in 0..mmap.num_entries {
         let Region { start, end } = mmap.entries[eidx];
 
         if end - start < mmu::PAGE_SIZE {
@@ -108,24 +278,31 @@ pub fn init(area_start: VirtAddr, maxpages: usize, info: &mut BootloaderInfo) {
 
         println_serial!("Free area {:x?}..{:x?}", pg_start, pg_end);
 
-        for pg in (pg_start..pg_end).step_by(mmu::PAGE_SIZE).rev() {
-            let index = pg / mmu::PAGE_SIZE;
+        let mut idx = pg_start / mmu::PAGE_SIZE;
+        let end_idx = pg_end / mmu::PAGE_SIZE;
 
-            if infos[index].next.is_some() {
-                continue;
-            }
+        // Free the area in biggest aligned blocks that fit, merging them with their buddies
+        while idx < end_idx {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| idx.is_po2_aligned(1 << o) && idx + (1 << o) <= end_idx)
+                .unwrap();
+
+            areas.page(idx).order = order as u8;
+            let page = areas.page(idx);
+            areas.free(page);
 
-            infos[index].next = *freep;
-            *freep = unsafe { Some(NonNull::new_unchecked(addr_of_mut!(infos[index]))) };
+            idx += 1 << order;
         }
     }
 }
 
 pub fn get_pg_alloc_region(info: &BootloaderInfo) -> (usize, PhysAddr, usize) {
-    let alloc_start = get_kernel_end(info).lpage_round_up();
+    // Bootloader modules are usually loaded right after the kernel and have to stay intact
+    let alloc_start = get_kernel_end(info).max(info.modules.end()).lpage_round_up();
 
     let mmap = &info.free_areas;
-    let max_addr = mmap.entries[mmap.num_entries - 1].end;
+    let max_addr = mmap.entries[mmap.num_entries - 1].end.max(info.modules.end());
     let maxpages = max_addr.div_ceil(mmu::PAGE_SIZE);
     let size_bytes = maxpages * size_of::<PageInfo>();
     let size_rounded = size_bytes.lpage_round_up();
@@ -151,7 +328,25 @@ fn get_kernel_end(info: &BootloaderInfo) -> usize {
     kernel_end
 }
 
-pub fn alloc_page() -> &'static mut PageInfo {
-    // This should not die on OOM
-    PageInfo::alloc().expect("pg_alloc: out of memory")
+pub fn alloc_page() -> Result<&'static mut PageInfo, OutOfMemory> {
+    alloc_pages(0)
+}
+
+/// Allocate a physically contiguous block of 2^order pages, aligned to its size. Returns info of the
+/// first page, which refcount of the whole block is kept in.
+pub fn alloc_pages(order: usize) -> Result<&'static mut PageInfo, OutOfMemory> {
+    assert!(order <= MAX_ORDER, "pg_alloc: order too big");
+
+    PageInfo::alloc(order).ok_or(OutOfMemory)
+}
+
+/// Return a block from `alloc_pages` that isn't refcounted
+pub fn free_pages(page: &mut PageInfo) {
+    page.free();
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        free_blocks: FREE_AREAS.lock().counts,
+    }
 }
@@ -5,14 +5,14 @@
 use core::fmt;
 
 use crate::arch::{self, LeafDirEntry, LeafDirEntryLarge};
-use crate::bootloader::BootloaderInfo;
 use crate::mm::pg_alloc::{self, PageInfo};
+use crate::mm::OutOfMemory;
 use crate::types::PowerOfTwoOps;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysAddr(pub usize);
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VirtAddr(pub usize);
 
 pub trait Address: From<usize> {
@@ -108,33 +108,68 @@ impl VirtAddr {
 pub trait RegisterFrameOps: fmt::Display {
     fn new_userspace() -> Self;
     fn set_program_counter(&mut self, addr: usize);
+    fn set_stack_pointer(&mut self, addr: usize);
+    fn set_syscall_result(&mut self, val: u64);
 }
 
-pub trait RootPageDirOps {
-    fn new() -> Self;
-    fn new_userspace(info: &BootloaderInfo) -> Self;
+pub trait RootPageDirOps: Sized {
+    fn new() -> Result<Self, OutOfMemory>;
+    fn new_userspace() -> Result<Self, OutOfMemory>;
     fn switch_to_this(&self);
+    /// Find the leaf entry of `addr`. With `create`, missing intermediate directories are
+    /// allocated, and `None` means that memory ran out.
     fn walk_dir(&mut self, addr: VirtAddr, create: bool) -> Option<&mut LeafDirEntry>;
     fn walk_dir_large(&mut self, addr: VirtAddr, create: bool) -> Option<&mut LeafDirEntryLarge>;
-    fn map_page_at_addr(&mut self, page: &mut PageInfo, addr: VirtAddr, perms: usize);
+    fn map_page_at_addr(
+        &mut self,
+        page: &mut PageInfo,
+        addr: VirtAddr,
+        perms: usize,
+    ) -> Result<(), OutOfMemory>;
     fn unmap_page_at_addr(&mut self, addr: VirtAddr);
     fn map_region(&mut self, from: VirtAddr, to: PhysAddr, pages: usize, perms: usize);
     fn map_region_large(&mut self, from: VirtAddr, to: PhysAddr, lpages: usize, perms: usize);
     fn unmap_region(&mut self, from: VirtAddr, pages: usize);
     fn unmap_region_large(&mut self, from: VirtAddr, lpages: usize);
     fn change_range_perms(&mut self, from: VirtAddr, size: usize, perms: usize);
-    fn is_region_user_accessible(&mut self, from: VirtAddr, to: VirtAddr) -> bool;
-
-    fn alloc_range(&mut self, addr: VirtAddr, size: usize, perms: usize) {
+    fn fork(&mut self) -> Result<Self, OutOfMemory>;
+    fn copy_on_write(&mut self, addr: VirtAddr) -> Result<bool, OutOfMemory>;
+    /// Number of pages mapped in the user half
+    fn resident_pages(&mut self) -> usize;
+    fn release(&mut self);
+
+    /// Map fresh pages over a range. If memory runs out, pages mapped so far are unmapped again.
+    fn alloc_range(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        perms: usize,
+    ) -> Result<(), OutOfMemory> {
         println!("Alloc range {:#x}..{:#x}, {:#b}", addr, addr + size, perms);
 
         let beg = addr.page_round_down();
         let end = (addr + size).page_round_up();
 
         for page_addr in (beg.0..end.0).step_by(arch::mmu::PAGE_SIZE) {
-            let page = pg_alloc::alloc_page();
+            let mapped = pg_alloc::alloc_page().and_then(|page| {
+                let mapped = self.map_page_at_addr(page, VirtAddr(page_addr), perms);
+
+                if mapped.is_err() {
+                    pg_alloc::free_pages(page);
+                }
+
+                mapped
+            });
 
-            self.map_page_at_addr(page, VirtAddr(page_addr), perms);
+            if let Err(err) = mapped {
+                for done in (beg.0..page_addr).step_by(arch::mmu::PAGE_SIZE) {
+                    self.unmap_page_at_addr(VirtAddr(done));
+                }
+
+                return Err(err);
+            }
         }
+
+        Ok(())
     }
 }
@@ -0,0 +1,62 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+pub use crate::arch::uaccess::with_user_access;
+use crate::arch::{self, uaccess};
+use crate::mm::types::VirtAddr;
+
+/// User pointer is either outside of userspace or points to memory that isn't mapped or writable
+#[derive(Debug)]
+pub struct BadAddress;
+
+/// Ensure the whole range is in userspace, so that user can't make the kernel access its own memory
+fn check_range(addr: VirtAddr, len: usize) -> Result<(), BadAddress> {
+    let end = addr.0.checked_add(len).ok_or(BadAddress)?;
+
+    if end > arch::USER_SPACE_END.0 {
+        return Err(BadAddress);
+    }
+
+    Ok(())
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), BadAddress> {
+    check_range(src, dst.len())?;
+
+    let left = uaccess::copy_user(dst.as_mut_ptr(), src.0 as *const u8, dst.len());
+
+    if left != 0 {
+        return Err(BadAddress);
+    }
+
+    Ok(())
+}
+
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), BadAddress> {
+    check_range(dst, src.len())?;
+
+    let left = uaccess::copy_user(dst.0 as *mut u8, src.as_ptr(), src.len());
+
+    if left != 0 {
+        return Err(BadAddress);
+    }
+
+    Ok(())
+}
+
+/// Copy NUL-terminated string into `dst` and return its length. If the string doesn't fit, `dst`
+/// is filled completely and isn't terminated. String that runs into the end of userspace is a bad
+/// address.
+pub fn strncpy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<usize, BadAddress> {
+    let max = arch::USER_SPACE_END.0.checked_sub(src.0).ok_or(BadAddress)?.min(dst.len());
+
+    let len = uaccess::strncpy_user(dst.as_mut_ptr(), src.0 as *const u8, max);
+    let len = usize::try_from(len).map_err(|_| BadAddress)?;
+
+    if len == max && max < dst.len() {
+        return Err(BadAddress);
+    }
+
+    Ok(len)
+}
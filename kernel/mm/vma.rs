@@ -0,0 +1,444 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::NonNull;
+
+use crate::arch::{self, mmu, RootPageDir};
+use crate::mm::types::{RootPageDirOps, VirtAddr};
+use crate::mm::{pg_alloc, OutOfMemory};
+use crate::types::PowerOfTwoOps;
+
+/// Virtual memory area: a page-aligned range of user address space with the same permissions and
+/// source of contents. Its pages are allocated when they are first touched.
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    /// Permissions pages are mapped with
+    pub perms: usize,
+    pub backing: Backing,
+    /// Lowest address a stack can grow down to. The page below it is left unmapped as a guard.
+    pub grows_down_to: Option<VirtAddr>,
+}
+
+/// Kind of access that faulted
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Reason an area couldn't be added
+#[derive(Debug)]
+pub enum AddError {
+    /// Area overlaps an existing one or room reserved for a stack to grow into
+    Overlaps,
+    OutOfMemory,
+}
+
+/// Reason pages of a range couldn't be populated
+#[derive(Debug)]
+pub enum PopulateError {
+    /// Part of the range isn't covered by any area
+    NotMapped,
+    OutOfMemory,
+}
+
+#[derive(Clone, Copy)]
+pub enum Backing {
+    /// Zero-filled memory
+    Anonymous,
+    /// Contents of a file, e.g. an ELF segment, which start at `offset` bytes into the area. Memory
+    /// outside of them is zero-filled.
+    File { data: &'static [u8], offset: usize },
+}
+
+impl Vma {
+    pub fn anonymous(start: VirtAddr, size: usize, perms: usize) -> Self {
+        Self::with_backing(start, size, perms, Backing::Anonymous)
+    }
+
+    pub fn file(
+        start: VirtAddr,
+        size: usize,
+        perms: usize,
+        data: &'static [u8],
+        offset: usize,
+    ) -> Self {
+        Self::with_backing(start, size, perms, Backing::File { data, offset })
+    }
+
+    /// Writable stack of `size` bytes below `top`, which grows on faults up to `max_size`
+    pub fn stack(top: VirtAddr, size: usize, max_size: usize) -> Self {
+        let perms = mmu::WRITABLE | mmu::USER_ACCESSIBLE | mmu::NON_EXECUTABLE;
+
+        Vma {
+            grows_down_to: Some(VirtAddr(top.0 - max_size)),
+            ..Self::anonymous(VirtAddr(top.0 - size), size, perms)
+        }
+    }
+
+    fn with_backing(start: VirtAddr, size: usize, perms: usize, backing: Backing) -> Self {
+        assert!(start.is_page_aligned());
+
+        Vma {
+            start,
+            end: (start + size).page_round_up(),
+            perms,
+            backing,
+            grows_down_to: None,
+        }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    /// Whether permissions of this area allow an access from userspace
+    fn allows(&self, access: Access) -> bool {
+        let readable = self.perms & mmu::USER_ACCESSIBLE != 0;
+
+        match access {
+            Access::Read => readable,
+            Access::Write => readable && self.perms & mmu::WRITABLE != 0,
+            Access::Execute => readable && self.perms & mmu::NON_EXECUTABLE == 0,
+        }
+    }
+
+    /// Range taken by this area, including room for a stack to grow into and its guard page
+    fn reserved(&self) -> (VirtAddr, VirtAddr) {
+        let start =
+            self.grows_down_to.map_or(self.start, |limit| VirtAddr(limit.0 - mmu::PAGE_SIZE));
+
+        (start, self.end)
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let (beg, fin) = self.reserved();
+
+        beg < end && start < fin
+    }
+
+    /// Cut the area at `addr`, keeping the lower part and returning the upper one
+    fn split_off(&mut self, addr: VirtAddr) -> Vma {
+        let delta = addr.0 - self.start.0;
+        let backing = match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { data, offset } if delta <= offset => Backing::File {
+                data,
+                offset: offset - delta,
+            },
+            Backing::File { data, offset } => Backing::File {
+                data: &data[(delta - offset).min(data.len())..],
+                offset: 0,
+            },
+        };
+
+        let upper = Vma {
+            start: addr,
+            backing,
+            grows_down_to: None,
+            ..*self
+        };
+
+        self.end = addr;
+
+        upper
+    }
+
+    /// Allocate the page at `addr`, fill it and map it with permissions of this area and `extra`
+    fn fault_in(
+        &self,
+        root_dir: &mut RootPageDir,
+        addr: VirtAddr,
+        extra: usize,
+    ) -> Result<(), OutOfMemory> {
+        let page_addr = addr.page_round_down();
+        let page = pg_alloc::alloc_page()?;
+
+        if let Backing::File { data, offset } = self.backing {
+            let page_offset = page_addr.0 - self.start.0;
+            let from = offset.max(page_offset);
+            let to = (offset + data.len()).min(page_offset + mmu::PAGE_SIZE);
+
+            if from < to {
+                let vaddr = page.to_physaddr().into_vaddr();
+                let dst = unsafe { vaddr.into_slice_mut(mmu::PAGE_SIZE) };
+
+                dst[from - page_offset..to - page_offset]
+                    .copy_from_slice(&data[from - offset..to - offset]);
+            }
+        }
+
+        let mapped = root_dir.map_page_at_addr(page, page_addr, self.perms | extra);
+
+        if mapped.is_err() {
+            pg_alloc::free_pages(page);
+        }
+
+        mapped
+    }
+}
+
+/// Memory areas of a process. Like other resources of a process, this is a handle that has to be
+/// freed explicitly. Areas are only changed through a mutable reference to the handle.
+#[derive(Clone, Copy)]
+pub struct VmaList {
+    areas: NonNull<Vec<Vma>>,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        VmaList {
+            areas: NonNull::from(Box::leak(Box::default())),
+        }
+    }
+
+    pub fn free(self) {
+        drop(unsafe { Box::from_raw(self.areas.as_ptr()) });
+    }
+
+    /// Replace areas of this list with those of another one, e.g. of a process being forked
+    pub fn copy_from(&mut self, other: &Self) -> Result<(), OutOfMemory> {
+        let areas = self.areas_mut();
+
+        areas.clear();
+        areas.try_reserve(other.areas().len()).map_err(|_| OutOfMemory)?;
+        areas.extend_from_slice(other.areas());
+
+        Ok(())
+    }
+
+    /// Add an area, which mustn't overlap existing ones. Anonymous one is merged into an alike area
+    /// that ends where it starts, so that a growing heap stays in one piece.
+    pub fn add(&mut self, vma: Vma) -> Result<(), AddError> {
+        let (start, end) = vma.reserved();
+
+        if !self.is_free(start, end) {
+            return Err(AddError::Overlaps);
+        }
+
+        let areas = self.areas_mut();
+
+        if let Backing::Anonymous = vma.backing {
+            let prev = areas.iter_mut().find(|prev| {
+                prev.end == vma.start
+                    && prev.perms == vma.perms
+                    && matches!(prev.backing, Backing::Anonymous)
+                    && prev.grows_down_to.is_none()
+            });
+
+            if let Some(prev) = prev {
+                prev.end = vma.end;
+
+                return Ok(());
+            }
+        }
+
+        areas.try_reserve(1).map_err(|_| OutOfMemory)?;
+        areas.push(vma);
+
+        Ok(())
+    }
+
+    /// Whether a range is clear of areas and of room reserved for stacks to grow into
+    fn is_free(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        !self.areas().iter().any(|vma| vma.overlaps(start, end))
+    }
+
+    /// Lowest free range of `size` bytes at or above `from`
+    pub fn find_free(&self, from: VirtAddr, size: usize) -> Option<VirtAddr> {
+        let mut start = from;
+
+        loop {
+            let end = VirtAddr(start.0.checked_add(size)?);
+
+            if end > arch::USER_SPACE_END {
+                return None;
+            }
+
+            let areas = self.areas().iter();
+
+            match areas.filter(|vma| vma.overlaps(start, end)).map(|vma| vma.end).max() {
+                Some(next) => start = next,
+                None => return Some(start),
+            }
+        }
+    }
+
+    /// Remove a page-aligned range from areas and unmap its pages. Areas partially inside it are
+    /// cut.
+    pub fn unmap(
+        &mut self,
+        root_dir: &mut RootPageDir,
+        start: VirtAddr,
+        end: VirtAddr,
+    ) -> Result<(), OutOfMemory> {
+        self.split_at(start)?;
+        self.split_at(end)?;
+
+        let inside = |vma: &Vma| start <= vma.start && vma.end <= end;
+
+        for vma in self.areas().iter().filter(|vma| inside(vma)) {
+            for page_addr in (vma.start.0..vma.end.0).step_by(mmu::PAGE_SIZE) {
+                root_dir.unmap_page_at_addr(VirtAddr(page_addr));
+            }
+        }
+
+        self.areas_mut().retain(|vma| !inside(vma));
+
+        Ok(())
+    }
+
+    /// Change permissions of a page-aligned range. Returns false if areas don't cover all of it.
+    pub fn protect(
+        &mut self,
+        root_dir: &mut RootPageDir,
+        start: VirtAddr,
+        end: VirtAddr,
+        perms: usize,
+    ) -> Result<bool, OutOfMemory> {
+        let mut addr = start;
+
+        while addr < end {
+            let Some(vma) = self.find(addr) else {
+                return Ok(false);
+            };
+
+            addr = vma.end;
+        }
+
+        self.split_at(start)?;
+        self.split_at(end)?;
+
+        let inside = |vma: &&mut Vma| start <= vma.start && vma.end <= end;
+
+        for vma in self.areas_mut().iter_mut().filter(inside) {
+            vma.perms = perms;
+        }
+
+        root_dir.change_range_perms(start, end.0 - start.0, perms);
+
+        Ok(true)
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas().iter().find(|vma| vma.contains(addr))
+    }
+
+    /// Map the page at `addr`, which isn't present. Returns false if no area allows the access.
+    pub fn handle_fault(
+        &mut self,
+        root_dir: &mut RootPageDir,
+        addr: VirtAddr,
+        access: Access,
+    ) -> Result<bool, OutOfMemory> {
+        let idx = self.areas().iter().position(|vma| vma.contains(addr));
+
+        let Some(idx) = idx.or_else(|| self.grow_stack(addr)) else {
+            return Ok(false);
+        };
+
+        let vma = &self.areas()[idx];
+
+        if !vma.allows(access) {
+            return Ok(false);
+        }
+
+        vma.fault_in(root_dir, addr, 0)?;
+
+        Ok(true)
+    }
+
+    /// Map every page of a range that isn't already, so that the kernel can fill it without
+    /// faulting. Pages are mapped with `extra` permissions in addition to those of their areas.
+    pub fn populate(
+        &self,
+        root_dir: &mut RootPageDir,
+        addr: VirtAddr,
+        size: usize,
+        extra: usize,
+    ) -> Result<(), PopulateError> {
+        let beg = addr.page_round_down();
+        let end = (addr + size).page_round_up();
+
+        for page_addr in (beg.0..end.0).step_by(mmu::PAGE_SIZE).map(VirtAddr) {
+            let vma = self.find(page_addr).ok_or(PopulateError::NotMapped)?;
+
+            if root_dir.walk_dir(page_addr, false).is_none() {
+                vma.fault_in(root_dir, page_addr, extra)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Split the area containing `addr` in two, so that no area crosses it
+    fn split_at(&mut self, addr: VirtAddr) -> Result<(), OutOfMemory> {
+        let areas = self.areas_mut();
+
+        areas.try_reserve(1).map_err(|_| OutOfMemory)?;
+
+        if let Some(vma) = areas.iter_mut().find(|vma| vma.contains(addr) && vma.start != addr) {
+            let upper = vma.split_off(addr);
+
+            areas.push(upper);
+        }
+
+        Ok(())
+    }
+
+    /// Extend a stack down to `addr`, if it's within the stack's limit. Returns index of the stack.
+    fn grow_stack(&mut self, addr: VirtAddr) -> Option<usize> {
+        let idx = self.areas().iter().position(|vma| {
+            vma.grows_down_to.map_or(false, |limit| (limit..vma.start).contains(&addr))
+        })?;
+
+        self.areas_mut()[idx].start = addr.page_round_down();
+
+        Some(idx)
+    }
+
+    fn areas(&self) -> &Vec<Vma> {
+        unsafe { self.areas.as_ref() }
+    }
+
+    fn areas_mut(&mut self) -> &mut Vec<Vma> {
+        unsafe { self.areas.as_mut() }
+    }
+}
+
+impl From<OutOfMemory> for AddError {
+    fn from(_: OutOfMemory) -> Self {
+        AddError::OutOfMemory
+    }
+}
+
+impl fmt::Display for AddError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddError::Overlaps => write!(f, "area overlaps existing ones"),
+            AddError::OutOfMemory => write!(f, "{OutOfMemory}"),
+        }
+    }
+}
+
+impl From<OutOfMemory> for PopulateError {
+    fn from(_: OutOfMemory) -> Self {
+        PopulateError::OutOfMemory
+    }
+}
+
+impl fmt::Display for PopulateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PopulateError::NotMapped => write!(f, "memory isn't mapped"),
+            PopulateError::OutOfMemory => write!(f, "{OutOfMemory}"),
+        }
+    }
+}
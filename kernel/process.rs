@@ -2,16 +2,40 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::bootloader::BootloaderInfo;
-use crate::mm::types::{RegisterFrameOps, RootPageDirOps};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use abi::{MAX_ARGS, MAX_ARGS_SIZE};
+
+use crate::elf::{Elf, LoadError};
+use crate::mm::kstack::{self, KernelStack};
+use crate::mm::types::{RegisterFrameOps, RootPageDirOps, VirtAddr};
+use crate::mm::vma::VmaList;
+use crate::sched::WaitQueue;
 use crate::{arch, elf};
 
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Copy, Clone)]
 pub struct Process {
     pub root_dir: arch::RootPageDir,
     pub registers: arch::RegisterFrame,
     pub state: State,
     pub name: &'static str,
+    pub pid: u64,
+    pub parent: Option<u64>,
+    pub kernel_stack: KernelStack,
+    pub fpu_state: arch::FpuState,
+    pub vmas: VmaList,
+    /// Start of the heap, right after the program image
+    pub brk_start: VirtAddr,
+    /// End of the heap, which the program moves with `brk`
+    pub brk: VirtAddr,
+    /// Address of the thread control block, which TLS is found relative to
+    pub thread_pointer: VirtAddr,
+    /// Log syscalls of this process to serial
+    pub strace: bool,
+    /// Set if the process was suspended inside the kernel and has to be resumed there
+    pub kernel_context: Option<arch::KernelContext>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -19,19 +43,207 @@ pub enum State {
     Runnable,
     Running,
     Stopped,
+    /// Suspended inside the kernel until an event from given queue happens
+    Blocked(WaitQueue),
+    /// Sleeping until the tick counter reaches given value
+    Sleeping(u64),
+    /// Waiting for a child with given PID to terminate
+    Waiting(u64),
+    /// Terminated, but the exit code hasn't been collected by the parent yet
+    Zombie(u64),
+    /// Terminated and can be removed from the scheduler
+    Dead,
+}
+
+/// Arguments and environment of a program, kept in the kernel while its address space is built.
+/// Strings are stored one after another with terminating NULs, arguments first.
+pub struct ProgramArgs {
+    strings: [u8; MAX_ARGS_SIZE],
+    size: usize,
+    argc: usize,
+    envc: usize,
+}
+
+impl ProgramArgs {
+    pub fn new() -> Self {
+        Self {
+            strings: [0; MAX_ARGS_SIZE],
+            size: 0,
+            argc: 0,
+            envc: 0,
+        }
+    }
+
+    /// Space left for strings. A string written there is added with `commit()`.
+    pub fn spare(&mut self) -> &mut [u8] {
+        &mut self.strings[self.size..]
+    }
+
+    /// Add a string of length `len`, followed by NUL, that was written to `spare()`. Environment
+    /// variables can't be followed by arguments.
+    pub fn commit(&mut self, len: usize, env: bool) -> Result<(), ()> {
+        if self.argc + self.envc == MAX_ARGS || self.size + len >= MAX_ARGS_SIZE {
+            return Err(());
+        }
+
+        if env {
+            self.envc += 1;
+        } else if self.envc == 0 {
+            self.argc += 1;
+        } else {
+            return Err(());
+        }
+
+        self.strings[self.size + len] = 0;
+        self.size += len + 1;
+
+        Ok(())
+    }
+
+    pub fn push_arg(&mut self, arg: &str) -> Result<(), ()> {
+        let spare = self.spare();
+
+        spare.get_mut(..arg.len()).ok_or(())?.copy_from_slice(arg.as_bytes());
+
+        self.commit(arg.len(), false)
+    }
+
+    /// All strings, arguments first
+    pub fn strings(&self) -> &[u8] {
+        &self.strings[..self.size]
+    }
+
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+
+    pub fn envc(&self) -> usize {
+        self.envc
+    }
 }
 
 impl Process {
-    pub fn from_elf(name: &'static str, bytes: &[u8], info: &BootloaderInfo) -> Self {
-        let mut process = Process {
-            root_dir: arch::RootPageDir::new_userspace(info),
+    /// Create a process with empty registers around given address space, which is released if
+    /// anything else can't be allocated
+    fn new(
+        name: &'static str,
+        parent: Option<u64>,
+        mut root_dir: arch::RootPageDir,
+    ) -> Result<Self, kstack::AllocError> {
+        let kernel_stack = KernelStack::alloc().map_err(|err| {
+            root_dir.release();
+            err
+        })?;
+
+        let fpu_state = arch::FpuState::alloc().map_err(|err| {
+            root_dir.release();
+            kernel_stack.free();
+            err
+        })?;
+
+        Ok(Process {
+            root_dir,
             registers: arch::RegisterFrame::new_userspace(),
             state: State::Runnable,
             name,
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            parent,
+            kernel_stack,
+            fpu_state,
+            vmas: VmaList::new(),
+            brk_start: VirtAddr(0),
+            brk: VirtAddr(0),
+            thread_pointer: VirtAddr(0),
+            strace: false,
+            kernel_context: None,
+        })
+    }
+
+    pub fn from_elf(name: &'static str, bytes: &'static [u8]) -> Result<Self, LoadError> {
+        let image = elf::parse(bytes)?;
+
+        let mut args = ProgramArgs::new();
+
+        args.push_arg(name).expect("Program name is too long");
+
+        let mut process = Self::new(name, None, arch::RootPageDir::new_userspace()?)?;
+
+        if let Err(err) = elf::load(&mut process, &image, &args) {
+            process.free();
+            return Err(err);
+        }
+
+        Ok(process)
+    }
+
+    /// Start running a new program in this process. Old address space is left for the caller to
+    /// release, since it may still be loaded. On failure, the process is left intact.
+    pub fn exec(
+        &mut self,
+        name: &'static str,
+        image: &Elf<'static>,
+        args: &ProgramArgs,
+    ) -> Result<(), LoadError> {
+        let mut root_dir = arch::RootPageDir::new_userspace()?;
+
+        let fpu_state = arch::FpuState::alloc().map_err(|err| {
+            root_dir.release();
+            err
+        })?;
+
+        let mut new = Process {
+            root_dir,
+            registers: arch::RegisterFrame::new_userspace(),
+            name,
+            fpu_state,
+            vmas: VmaList::new(),
+            brk_start: VirtAddr(0),
+            brk: VirtAddr(0),
+            thread_pointer: VirtAddr(0),
+            ..*self
         };
 
-        elf::load(&mut process, bytes);
+        if let Err(err) = elf::load(&mut new, image, args) {
+            new.root_dir.release();
+            new.fpu_state.free();
+            new.vmas.free();
+            return Err(err);
+        }
+
+        self.fpu_state.free();
+        self.vmas.free();
+
+        *self = new;
+
+        Ok(())
+    }
+
+    /// Create a child which shares memory with this process copy-on-write and continues from the
+    /// same point, except that the syscall returns 0 to it
+    pub fn fork(&mut self) -> Result<Self, kstack::AllocError> {
+        let mut child = Self::new(self.name, Some(self.pid), self.root_dir.fork()?)?;
+
+        child.registers = self.registers;
+        child.registers.set_syscall_result(0);
+        child.fpu_state.copy_from(&self.fpu_state);
+        child.brk_start = self.brk_start;
+        child.brk = self.brk;
+        child.thread_pointer = self.thread_pointer;
+        child.strace = self.strace;
+
+        if let Err(err) = child.vmas.copy_from(&self.vmas) {
+            child.free();
+            return Err(err.into());
+        }
+
+        Ok(child)
+    }
 
-        process
+    /// Release everything the process owns. Its address space must not be loaded.
+    pub fn free(&mut self) {
+        self.root_dir.release();
+        self.kernel_stack.free();
+        self.fpu_state.free();
+        self.vmas.free();
     }
 }
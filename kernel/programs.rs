@@ -0,0 +1,52 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::vec::Vec;
+use core::slice;
+
+use tar_parser::Archive;
+
+use crate::bootloader::BootloaderInfo;
+use crate::mm::types::PhysAddr;
+use crate::spinlock::Mutex;
+
+/// Archives of the initial ramdisk, one per bootloader module. Their memory is never freed.
+static ARCHIVES: Mutex<Vec<Archive<'static>>> = Mutex::new(Vec::new());
+
+/// User program stored in the initial ramdisk
+#[derive(Clone, Copy)]
+pub struct Program {
+    pub name: &'static str,
+    pub elf: &'static [u8],
+}
+
+/// Mount bootloader modules that are tar archives as the initial ramdisk
+pub fn init(info: &BootloaderInfo) {
+    let mut archives = ARCHIVES.lock();
+
+    for module in info.modules.iter() {
+        let addr = PhysAddr(module.start).into_vaddr();
+        let data = unsafe { slice::from_raw_parts(addr.0 as *const u8, module.end - module.start) };
+
+        match Archive::parse(data) {
+            Ok(archive) => archives.push(archive),
+            Err(err) => println!("Skipping module at {:#x}: {}", module.start, err),
+        }
+    }
+
+    if archives.is_empty() {
+        println!("No initial ramdisk was loaded");
+    }
+}
+
+/// Look up a program in the initial ramdisk by name
+pub fn find(name: &str) -> Option<Program> {
+    let archives = ARCHIVES.lock();
+    let file = archives.iter().find_map(|archive| archive.find(name))?;
+
+    Some(Program {
+        name: file.name,
+        elf: file.data,
+    })
+}
@@ -0,0 +1,93 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/// Fixed-size byte queue which doesn't need an allocator, so it can be used in statics
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns false if there is no space left, in which case the byte is dropped
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.buf[self.head];
+
+        self.head += 1;
+        self.head %= N;
+        self.len -= 1;
+
+        Some(byte)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ring_buffer::RingBuffer;
+
+    #[test]
+    fn simple() {
+        let mut ring = RingBuffer::<4>::new();
+
+        assert!(ring.is_empty());
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn full() {
+        let mut ring = RingBuffer::<2>::new();
+
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(!ring.push(3));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut ring = RingBuffer::<3>::new();
+
+        for byte in 0..10 {
+            assert!(ring.push(byte));
+            assert!(ring.push(byte + 100));
+            assert_eq!(ring.pop(), Some(byte));
+            assert_eq!(ring.pop(), Some(byte + 100));
+        }
+
+        assert!(ring.is_empty());
+    }
+}
@@ -2,13 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use core::convert::Infallible;
 use core::ops::{Deref, DerefMut};
 
-use crate::arch;
-use crate::bootloader::BootloaderInfo;
-use crate::process::{Process, State};
+use abi::EXIT_CODE_KILLED;
+
+use crate::elf::{Elf, LoadError};
+use crate::mm::types::RootPageDirOps;
+use crate::mm::{kstack, OutOfMemory};
+use crate::process::{Process, ProgramArgs, State};
 use crate::small_vec::SmallVec;
 use crate::spinlock::{Mutex, SpinlockGuard};
+use crate::{arch, mm, programs, timer};
 
 static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::empty());
 
@@ -33,50 +38,151 @@ impl Scheduler {
         }
     }
 
+    /// Pick a runnable process, skipping blocked and sleeping ones. If there are none, CPU idles until
+    /// an interrupt wakes someone up.
     fn get_next(&mut self) -> TaskSwitch {
-        for (idx, proc) in self.processes.iter_round_robin() {
+        let mut procs = self.processes.iter_round_robin();
+        let current = procs.next();
+
+        // Current process goes last, in case it yielded while remaining runnable
+        for (idx, proc) in procs.chain(current) {
             if proc.state == State::Runnable {
                 return TaskSwitch::NewTask(idx, *proc);
             }
         }
 
-        if let Some(current) = self.processes.current() && current.state == State::Running {
+        if let Some((_, current)) = current && current.state == State::Running {
             return TaskSwitch::SameTask(*current);
         }
 
         TaskSwitch::Idle
     }
 
+    fn find(&self, pred: impl Fn(&Process) -> bool) -> Option<usize> {
+        self.processes.iter_round_robin().find(|(_, proc)| pred(proc)).map(|(idx, _)| idx)
+    }
+
+    fn find_by_pid(&self, pid: u64) -> Option<&mut Process> {
+        let idx = self.find(|proc| proc.pid == pid)?;
+        self.processes.get(idx)
+    }
+
+    /// Child of the current process with given PID
+    fn find_child(&self, pid: u64) -> Option<usize> {
+        let ppid = self.processes.current().unwrap().pid;
+
+        self.find(|proc| proc.pid == pid && proc.parent == Some(ppid))
+    }
+
+    /// Free slots of terminated processes nobody is going to wait for
+    fn remove_dead(&mut self) {
+        let current = self.processes.current().map(|proc| proc.pid);
+
+        while let Some(idx) = self.find(|p| p.state == State::Dead && Some(p.pid) != current) {
+            self.remove(idx);
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let proc = self.processes.swap_remove(idx);
+
+        proc.kernel_stack.free();
+        proc.fpu_state.free();
+        proc.vmas.free();
+    }
+
+    /// Mark a process as terminated with given exit code and notify its parent, if any. Address
+    /// space is returned for the caller to release once it's not loaded.
+    fn terminate(&mut self, idx: usize, code: u64) -> arch::RootPageDir {
+        let proc = self.processes.get(idx).unwrap();
+        let pid = proc.pid;
+        let parent = proc.parent;
+
+        trace!("'{}' exited with code {:#x}", proc.name, code);
+
+        proc.state = if parent.is_some() {
+            State::Zombie(code)
+        } else {
+            State::Dead
+        };
+
+        let root_dir = proc.root_dir;
+
+        self.orphan_children(pid);
+
+        if let Some(ppid) = parent
+            && let Some(parent) = self.find_by_pid(ppid)
+            && parent.state == State::Waiting(pid)
+        {
+            parent.state = State::Runnable;
+        }
+
+        root_dir
+    }
+
+    /// Hand over children of an exiting process to no one. Zombies among them are not going to be
+    /// waited for anymore.
+    fn orphan_children(&mut self, pid: u64) {
+        for (idx, _) in self.processes.iter_round_robin() {
+            let proc = self.processes.get(idx).unwrap();
+
+            if proc.parent != Some(pid) {
+                continue;
+            }
+
+            proc.parent = None;
+
+            if let State::Zombie(_) = proc.state {
+                proc.state = State::Dead;
+            }
+        }
+    }
+
     fn set_current(&mut self, new_idx: usize) {
-        if let Some(current) = self.processes.current() {
+        if let Some(current) = self.processes.current() && current.state == State::Running {
             current.state = State::Runnable;
         }
 
         self.processes.set_current(new_idx);
 
-        self.processes.current().unwrap().state = State::Running;
+        let new = self.processes.current().unwrap();
+
+        new.state = State::Running;
+        new.kernel_context = None;
     }
 }
 
+/// Kind of event a blocked process can be woken up by
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum WaitQueue {
+    SerialInput,
+}
+
 enum TaskSwitch {
     NewTask(usize, Process),
     SameTask(Process),
     Idle,
 }
 
-pub fn init(info: &BootloaderInfo) {
-    static LOOP_ELF: &[u8] = include_bytes!("../build/bundle/loop");
-    static BKPT_ELF: &[u8] = include_bytes!("../build/bundle/breakpoint");
-    static HLWD_ELF: &[u8] = include_bytes!("../build/bundle/hello_world");
-    static READ_ELF: &[u8] = include_bytes!("../build/bundle/input");
+pub fn init() {
+    let spawn = |name, program| {
+        let elf = programs::find(program)
+            .unwrap_or_else(|| panic!("Program '{program}' is not in the initial ramdisk"))
+            .elf;
+
+        Process::from_elf(name, elf).unwrap_or_else(|err| panic!("Bad program '{program}': {err}"))
+    };
 
     let mut sched = Scheduler::new();
 
-    sched.processes.push_back(Process::from_elf("loop", LOOP_ELF, info));
-    sched.processes.push_back(Process::from_elf("breakpoint", BKPT_ELF, info));
-    sched.processes.push_back(Process::from_elf("loop 2", LOOP_ELF, info));
-    sched.processes.push_back(Process::from_elf("hello_world", HLWD_ELF, info));
-    sched.processes.push_back(Process::from_elf("input", READ_ELF, info));
+    sched.processes.push_back(spawn("loop", "loop"));
+    sched.processes.push_back(spawn("breakpoint", "breakpoint"));
+    sched.processes.push_back(spawn("loop 2", "loop"));
+    sched.processes.push_back(spawn("hello_world", "hello_world"));
+    sched.processes.push_back(spawn("input", "input"));
+    sched.processes.push_back(spawn("fork", "fork"));
+    sched.processes.push_back(spawn("exec", "exec"));
+    sched.processes.push_back(spawn("tls", "tls"));
 
     *SCHEDULER.lock() = sched;
 }
@@ -84,6 +190,8 @@ pub fn init(info: &BootloaderInfo) {
 pub fn next() -> ! {
     let mut sched = SCHEDULER.lock();
 
+    sched.remove_dead();
+
     match sched.get_next() {
         TaskSwitch::NewTask(new_idx, proc) => {
             trace!("switching to a new task '{}'", proc.name);
@@ -104,16 +212,199 @@ pub fn next() -> ! {
     }
 }
 
+/// Terminate the current process, e.g. after an unrecoverable fault, and switch to the next one
+pub fn kill_current() -> ! {
+    exit_current(EXIT_CODE_KILLED);
+}
+
+/// Terminate the current process, tear down its address space and notify the parent, if any
+pub fn exit_current(code: u64) -> ! {
+    let mut root_dir = {
+        let mut sched = SCHEDULER.lock();
+        let pid = sched.processes.current().unwrap().pid;
+        let idx = sched.find(|proc| proc.pid == pid).unwrap();
+
+        sched.terminate(idx, code)
+    };
+
+    // Can't free the page directory while it's still loaded
+    mm::switch_to_kernel_root_dir();
+
+    root_dir.release();
+
+    next();
+}
+
+/// Exit code of a terminated child. Returns `Ok(None)` if the child is still running and `Err` if
+/// the current process has no child with such PID.
+pub fn child_exit_code(pid: u64) -> Result<Option<u64>, ()> {
+    let sched = SCHEDULER.lock();
+    let idx = sched.find_child(pid).ok_or(())?;
+
+    match sched.processes.get(idx).unwrap().state {
+        State::Zombie(code) => Ok(Some(code)),
+        _ => Ok(None),
+    }
+}
+
+/// Free the slot of a terminated child once its exit code has been collected
+pub fn reap_child(pid: u64) {
+    let mut sched = SCHEDULER.lock();
+
+    if let Some(idx) = sched.find_child(pid) {
+        sched.remove(idx);
+    }
+}
+
+/// Free memory by killing the process that uses the most of it. Doesn't return if that is the
+/// current process. Returns false if there was nothing to free.
+pub fn oom_kill() -> bool {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.processes.current().map(|proc| proc.pid);
+
+    let victim = sched
+        .processes
+        .iter_round_robin()
+        .filter(|(_, proc)| !matches!(proc.state, State::Zombie(_) | State::Dead))
+        .map(|(idx, proc)| {
+            let mut root_dir = proc.root_dir;
+
+            (idx, root_dir.resident_pages())
+        })
+        .max_by_key(|&(_, pages)| pages);
+
+    let Some((idx, pages)) = victim.filter(|&(_, pages)| pages > 0) else {
+        return false;
+    };
+
+    let proc = sched.processes.get(idx).unwrap();
+
+    println!("Out of memory: killing '{}' (PID {}) using {} pages", proc.name, proc.pid, pages);
+
+    if Some(proc.pid) == current {
+        drop(sched);
+        kill_current();
+    }
+
+    let mut root_dir = sched.terminate(idx, EXIT_CODE_KILLED);
+
+    root_dir.release();
+
+    true
+}
+
+/// Duplicate the current process and return PID of the child
+pub fn fork_current() -> Result<u64, kstack::AllocError> {
+    let mut sched = SCHEDULER.lock();
+    let child = sched.processes.current().unwrap().fork()?;
+    let pid = child.pid;
+
+    if let Err(mut child) = sched.processes.try_push_back(child) {
+        child.free();
+
+        return Err(OutOfMemory.into());
+    }
+
+    Ok(pid)
+}
+
+/// Replace the image of the current process with given program and switch to the next process.
+/// PID, parent and children are kept. Returns only if the program couldn't be loaded, with the old
+/// image intact.
+pub fn exec_current(
+    name: &'static str,
+    image: &Elf<'static>,
+    args: &ProgramArgs,
+) -> Result<Infallible, LoadError> {
+    let mut old_root_dir = {
+        let mut proc = current();
+        let root_dir = proc.root_dir;
+
+        trace!("'{}' executes '{}'", proc.name, name);
+
+        if let Err(err) = proc.exec(name, image, args) {
+            // Loading leaves the kernel address space active
+            root_dir.switch_to_this();
+
+            return Err(err);
+        }
+
+        root_dir
+    };
+
+    // Can't free the page directory while it's still loaded
+    mm::switch_to_kernel_root_dir();
+
+    old_root_dir.release();
+
+    next();
+}
+
+/// Suspend the current process inside the kernel in given state, which is expected to be changed
+/// back to `Runnable` by whoever wakes it up. Returns once the process is scheduled again.
+pub fn block_current(state: State) {
+    current().state = state;
+
+    arch::save_context(suspend);
+}
+
+/// Block the current process until `wake_up()` is called for given queue
+pub fn sleep_on(queue: WaitQueue) {
+    block_current(State::Blocked(queue));
+}
+
+/// Make all processes blocked on given queue runnable. Returns true if there were any.
+pub fn wake_up(queue: WaitQueue) -> bool {
+    let sched = SCHEDULER.lock();
+    let mut woken = false;
+
+    while let Some(idx) = sched.find(|proc| proc.state == State::Blocked(queue)) {
+        sched.processes.get(idx).unwrap().state = State::Runnable;
+        woken = true;
+    }
+
+    woken
+}
+
+/// Timer callback for processes put to sleep by `sleep_until()`
+fn wake_sleeping(pid: u64) {
+    let sched = SCHEDULER.lock();
+
+    if let Some(proc) = sched.find_by_pid(pid)
+        && let State::Sleeping(_) = proc.state
+    {
+        proc.state = State::Runnable;
+    }
+}
+
+/// Suspend the current process until the tick counter reaches `deadline`
+pub fn sleep_until(deadline: u64) -> Result<(), ()> {
+    let pid = current().pid;
+
+    timer::add(deadline, wake_sleeping, pid)?;
+
+    block_current(State::Sleeping(deadline));
+
+    Ok(())
+}
+
+/// Let other processes run without leaving the kernel
+pub fn yield_current() {
+    block_current(State::Runnable);
+}
+
+extern "C" fn suspend(context: arch::KernelContext) -> ! {
+    current().kernel_context = Some(context);
+
+    next();
+}
+
 fn run(proc: Process) -> ! {
     arch::switch_to_process(proc);
 }
 
 fn idle() -> ! {
-    arch::interrupts::enable();
-
-    loop {
-        arch::asm::idle();
-    }
+    arch::idle();
 }
 
 impl Deref for ProcessGuard<'_> {
@@ -5,15 +5,21 @@
 use core::fmt;
 
 use crate::arch::uart;
+use crate::ring_buffer::RingBuffer;
+use crate::sched::{self, WaitQueue};
 use crate::spinlock::Mutex;
 
 type SerialImpl = uart::Uart;
 
 pub static SERIAL: Mutex<SerialImpl> = Mutex::new(SerialImpl {});
 
+/// Bytes received but not yet read by any process
+static INPUT: Mutex<RingBuffer<256>> = Mutex::new(RingBuffer::new());
+
 pub trait Serial {
     fn init();
     fn read_blocking(&self) -> u8;
+    fn read_nonblocking(&self) -> Option<u8>;
     fn write_blocking(&self, byte: u8);
 }
 
@@ -31,3 +37,34 @@ impl fmt::Write for SerialImpl {
 pub fn init() {
     SerialImpl::init();
 }
+
+/// Move received bytes from the hardware to the input buffer. Returns true if a process waiting for
+/// input was woken up.
+pub fn handle_interrupt() -> bool {
+    let serial = SERIAL.lock();
+    let mut input = INPUT.lock();
+
+    while let Some(byte) = serial.read_nonblocking() {
+        if !input.push(byte) {
+            trace!("serial: input buffer overflow");
+        }
+    }
+
+    let received = !input.is_empty();
+
+    drop(input);
+    drop(serial);
+
+    received && sched::wake_up(WaitQueue::SerialInput)
+}
+
+/// Read a byte of input, blocking the current process until one is available
+pub fn read() -> u8 {
+    loop {
+        if let Some(byte) = INPUT.lock().pop() {
+            return byte;
+        }
+
+        sched::sleep_on(WaitQueue::SerialInput);
+    }
+}
@@ -2,10 +2,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use alloc::alloc::{alloc, dealloc, handle_alloc_error};
+use core::alloc::Layout;
 use core::{mem, ptr};
 
-use crate::arch::mmu::PAGE_SIZE;
-use crate::mm::pg_alloc;
+const MIN_CAPACITY: usize = 4;
 
 pub struct SmallVec<T> {
     buf: *mut T,
@@ -14,6 +15,8 @@ pub struct SmallVec<T> {
     head: usize,
     tail: usize,
     view: usize,
+    /// Buffer is allocated on the heap and can be grown, as opposed to being borrowed from a slice
+    owned: bool,
 }
 
 impl<T> SmallVec<T> {
@@ -25,15 +28,19 @@ impl<T> SmallVec<T> {
             head: 0,
             tail: 0,
             view: 0,
+            owned: false,
         }
     }
 
     pub fn new() -> Self {
-        let page = pg_alloc::alloc_page();
-        let addr = page.to_physaddr().into_vaddr();
-        let slice = unsafe { addr.into_slice_mut(PAGE_SIZE) };
+        assert!(mem::size_of::<T>() != 0);
 
-        Self::from_slice(slice)
+        Self {
+            buf: Self::alloc_buf(MIN_CAPACITY),
+            cap: MIN_CAPACITY,
+            owned: true,
+            ..Self::empty()
+        }
     }
 
     pub fn from_slice<U>(slice: &mut [U]) -> Self {
@@ -50,14 +57,19 @@ impl<T> SmallVec<T> {
         Self {
             buf: aligned.cast::<T>(),
             len: 0,
-            cap: (slice.len() * mem::size_of::<U>() - offset) / mem::size_of::<T>(),
+            cap: (mem::size_of_val(slice) - offset) / mem::size_of::<T>(),
             head: 0,
             tail: 0,
             view: 0,
+            owned: false,
         }
     }
 
     pub fn push_back(&mut self, item: T) {
+        if self.owned && self.is_full() {
+            self.grow();
+        }
+
         assert!(self.len < self.cap, "small_vec: overflow");
 
         unsafe {
@@ -70,6 +82,18 @@ impl<T> SmallVec<T> {
         self.tail %= self.cap;
     }
 
+    /// Like `push_back`, but hand the element back if there's no room for it and the buffer can't
+    /// grow, e.g. because memory ran out
+    pub fn try_push_back(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() && !(self.owned && self.try_grow()) {
+            return Err(item);
+        }
+
+        self.push_back(item);
+
+        Ok(())
+    }
+
     pub fn pop_front(&mut self) -> Option<T> {
         if self.head == self.tail {
             return None;
@@ -87,6 +111,96 @@ impl<T> SmallVec<T> {
         Some(elem)
     }
 
+    /// Remove element at `idx`, moving the last element into its slot
+    pub fn swap_remove(&mut self, idx: usize) -> T {
+        assert!(self.is_valid_index(idx), "small_vec: bad index");
+
+        let last = (self.tail + self.cap - 1) % self.cap;
+
+        let elem = unsafe {
+            let elem = self.buf.add(idx).read();
+
+            if idx != last {
+                ptr::copy_nonoverlapping(self.buf.add(last), self.buf.add(idx), 1);
+            }
+
+            elem
+        };
+
+        self.len -= 1;
+        self.tail = last;
+
+        if self.view == last {
+            self.view = if idx == last { self.head } else { idx };
+        }
+
+        elem
+    }
+
+    fn grow(&mut self) {
+        if !self.try_grow() {
+            handle_alloc_error(Layout::array::<T>(self.cap * 2).unwrap());
+        }
+    }
+
+    /// Double the capacity. Elements that wrapped around to the start of the buffer are moved past
+    /// the old end, so that they keep their order. Returns false if memory ran out.
+    fn try_grow(&mut self) -> bool {
+        let new_cap = self.cap * 2;
+        let Some(new_buf) = Self::try_alloc_buf(new_cap) else {
+            return false;
+        };
+
+        unsafe {
+            let tail_part = self.cap - self.head;
+
+            ptr::copy_nonoverlapping(self.buf.add(self.head), new_buf.add(self.head), tail_part);
+            ptr::copy_nonoverlapping(self.buf, new_buf.add(self.cap), self.head);
+
+            dealloc(self.buf.cast(), Layout::array::<T>(self.cap).unwrap());
+        }
+
+        if self.view < self.head {
+            self.view += self.cap;
+        }
+
+        self.tail = self.cap + self.head;
+        self.buf = new_buf;
+        self.cap = new_cap;
+
+        true
+    }
+
+    fn alloc_buf(cap: usize) -> *mut T {
+        let layout = Layout::array::<T>(cap).unwrap();
+
+        Self::try_alloc_buf(cap).unwrap_or_else(|| handle_alloc_error(layout))
+    }
+
+    fn try_alloc_buf(cap: usize) -> Option<*mut T> {
+        let buf = unsafe { alloc(Layout::array::<T>(cap).unwrap()) };
+
+        (!buf.is_null()).then_some(buf.cast())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.cap
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&mut T> {
+        if self.is_valid_index(idx) {
+            unsafe { self.buf.add(idx).as_mut() }
+        } else {
+            None
+        }
+    }
+
+    fn is_valid_index(&self, idx: usize) -> bool {
+        self.len != 0
+            && idx >= self.head
+            && (idx < self.tail || (self.tail == self.head && idx < self.len))
+    }
+
     pub fn current(&self) -> Option<&mut T> {
         if self.len == 0 {
             None
@@ -96,8 +210,7 @@ impl<T> SmallVec<T> {
     }
 
     pub fn set_current(&mut self, new_view: usize) {
-        assert!(new_view >= self.head);
-        assert!(new_view < self.tail || (self.tail == self.head && new_view < self.len));
+        assert!(self.is_valid_index(new_view));
 
         self.view = new_view;
     }
@@ -119,6 +232,10 @@ impl<T> Drop for SmallVec<T> {
         while let Some(elem) = self.pop_front() {
             drop(elem);
         }
+
+        if self.owned {
+            unsafe { dealloc(self.buf.cast(), Layout::array::<T>(self.cap).unwrap()) };
+        }
     }
 }
 
@@ -183,7 +300,20 @@ mod tests {
 
         vec.push_back(1u64);
         vec.push_back(2);
+        assert!(!vec.is_full());
         vec.push_back(3);
+        assert!(vec.is_full());
+    }
+
+    #[test]
+    fn try_push_full() {
+        let mut storage = [0u64; 2];
+        let mut vec = SmallVec::from_slice(&mut storage);
+
+        assert_eq!(vec.try_push_back(1u64), Ok(()));
+        assert_eq!(vec.try_push_back(2), Ok(()));
+        assert_eq!(vec.try_push_back(3), Err(3));
+        assert_eq!(vec.pop_front(), Some(1));
     }
 
     #[test]
@@ -305,4 +435,63 @@ mod tests {
         assert_eq!(it.next(), Some((1, &10)));
         assert_eq!(it.next(), None);
     }
+
+    #[test]
+    fn swap_remove() {
+        let mut storage = [0u64; 4];
+        let mut vec = SmallVec::from_slice(&mut storage);
+
+        vec.push_back(0u64);
+        vec.push_back(10);
+        vec.push_back(20);
+        vec.push_back(30);
+
+        vec.set_current(3);
+
+        assert_eq!(vec.swap_remove(1), 10);
+        assert_eq!(vec.current(), Some(&mut 30));
+
+        let mut it = vec.iter_round_robin();
+
+        assert_eq!(it.next(), Some((1, &30)));
+        assert_eq!(it.next(), Some((2, &20)));
+        assert_eq!(it.next(), Some((0, &0)));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn grow() {
+        let mut vec = SmallVec::new();
+
+        for i in 0..100u64 {
+            vec.push_back(i);
+        }
+
+        vec.set_current(50);
+
+        assert_eq!(vec.swap_remove(10), 10);
+        assert_eq!(vec.get(10), Some(&mut 99));
+        assert_eq!(vec.current(), Some(&mut 50));
+        assert_eq!(vec.iter_round_robin().count(), 99);
+    }
+
+    #[test]
+    fn swap_remove_reuse() {
+        let mut storage = [0u64; 2];
+        let mut vec = SmallVec::from_slice(&mut storage);
+
+        vec.push_back(1u64);
+        vec.push_back(2);
+
+        assert_eq!(vec.swap_remove(0), 1);
+        assert_eq!(vec.swap_remove(0), 2);
+        assert_eq!(vec.current(), None);
+
+        vec.push_back(3);
+        vec.push_back(4);
+
+        assert_eq!(vec.get(0), Some(&mut 3));
+        assert_eq!(vec.get(1), Some(&mut 4));
+        assert_eq!(vec.get(2), None);
+    }
 }
@@ -2,22 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use core::convert::Infallible;
-use core::ops::{ControlFlow, FromResidual, Try};
-use core::{fmt, slice, str};
+use alloc::string::String;
+use core::mem::size_of;
+use core::{fmt, str};
 
-use crate::arch::RegisterFrame;
-use crate::mm::types::{Address, RootPageDirOps, VirtAddr};
-use crate::sched;
-use crate::serial::{Serial, SERIAL};
+use abi::{
+    ConvertToNumericResult, Error, NumericResult, MAX_PATH_LEN, PROT_EXEC, PROT_READ, PROT_WRITE,
+    SYSC_BRK, SYSC_EXEC, SYSC_EXIT, SYSC_FORK, SYSC_GETCH, SYSC_MMAP, SYSC_MPROTECT, SYSC_MUNMAP,
+    SYSC_SLEEP, SYSC_STRACE, SYSC_WAIT, SYSC_WRITE, SYSC_YIELD,
+};
 
-const SYSC_YIELD: u64 = 0;
-const SYSC_WRITE: u64 = 1;
-const SYSC_GETCH: u64 = 2;
+use crate::arch::{self, mmu, RegisterFrame};
+use crate::elf::LoadError;
+use crate::mm::kstack;
+use crate::mm::types::{Address, VirtAddr};
+use crate::mm::uaccess::{copy_from_user, copy_to_user, strncpy_from_user};
+use crate::mm::vma::{AddError, Vma};
+use crate::process::{ProgramArgs, State};
+use crate::types::PowerOfTwoOps;
+use crate::{elf, programs, sched, serial, time};
 
-const SYSR_OK: u64 = 0;
-const SYSR_ERR_NO_PERMISSIONS: u64 = 1;
-const SYSR_ERR_BAD_ARGS: u64 = 2;
+/// Size of kernel buffer user strings are printed through
+const WRITE_CHUNK_SIZE: usize = 256;
+
+/// Longest part of a string argument shown when tracing syscalls
+const STRACE_STR_LEN: usize = 64;
 
 #[repr(C, packed)]
 pub struct SyscallArgs {
@@ -40,117 +49,473 @@ impl From<RegisterFrame> for SyscallArgs {
     }
 }
 
-impl fmt::Display for SyscallArgs {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let num = self.number;
-        let arg1 = self.arg1;
-        let arg2 = self.arg2;
-        let arg3 = self.arg3;
-        let arg4 = self.arg4;
-
-        write!(f, "num={} args: {:x} {:x} {:x} {:x}", num, arg1, arg2, arg3, arg4)
+impl SyscallArgs {
+    fn get(&self, idx: usize) -> u64 {
+        [self.arg1, self.arg2, self.arg3, self.arg4][idx]
     }
 }
 
-#[must_use]
-enum NumericResult<T> {
-    Ok(T),
-    Err(u64),
+/// What an argument or return value means. Arguments are validated and traced according to it.
+#[derive(Clone, Copy)]
+enum ArgKind {
+    Int,
+    Pid,
+    /// Address in user space, possibly NULL
+    UserPtr,
+    /// Size of user memory
+    Len,
+    /// Pointer to a NUL-terminated user string
+    Str,
+    /// `PROT_*` flags
+    Prot,
 }
 
-trait ConvertToNumericResult<T> {
-    fn convert_err(self, err: u64) -> NumericResult<T>;
+struct Syscall {
+    number: u64,
+    name: &'static str,
+    handler: fn(&SyscallArgs) -> NumericResult<u64>,
+    args: &'static [ArgKind],
+    ret: ArgKind,
 }
 
-impl<T, E> ConvertToNumericResult<T> for Result<T, E> {
-    fn convert_err(self, err: u64) -> NumericResult<T> {
-        match self {
-            Ok(t) => NumericResult::Ok(t),
-            Err(_) => NumericResult::Err(err),
-        }
+/// Syscalls indexed by their numbers. Order is checked when the table is built at compile time.
+static SYSCALLS: [Syscall; 13] = {
+    use ArgKind::{Int, Len, Pid, Prot, Str, UserPtr};
+
+    let table = [
+        entry(SYSC_YIELD, "yield", sched_yield, &[], Int),
+        entry(SYSC_WRITE, "write", write, &[UserPtr, Len], Int),
+        entry(SYSC_GETCH, "getch", getch, &[], Int),
+        entry(SYSC_EXIT, "exit", exit, &[Int], Int),
+        entry(SYSC_WAIT, "wait", wait, &[Pid, UserPtr], Int),
+        entry(SYSC_SLEEP, "sleep", sleep, &[Int], Int),
+        entry(SYSC_FORK, "fork", fork, &[], Pid),
+        entry(SYSC_EXEC, "exec", exec, &[Str, UserPtr, UserPtr], Int),
+        entry(SYSC_BRK, "brk", brk, &[UserPtr], UserPtr),
+        entry(SYSC_MMAP, "mmap", mmap, &[UserPtr, Len, Prot], UserPtr),
+        entry(SYSC_MUNMAP, "munmap", munmap, &[UserPtr, Len], Int),
+        entry(SYSC_MPROTECT, "mprotect", mprotect, &[UserPtr, Len, Prot], Int),
+        entry(SYSC_STRACE, "strace", strace, &[Int], Int),
+    ];
+
+    let mut idx = 0;
+
+    while idx < table.len() {
+        assert!(table[idx].number == idx as u64, "syscall table is out of order");
+        idx += 1;
+    }
+
+    table
+};
+
+const fn entry(
+    number: u64,
+    name: &'static str,
+    handler: fn(&SyscallArgs) -> NumericResult<u64>,
+    args: &'static [ArgKind],
+    ret: ArgKind,
+) -> Syscall {
+    Syscall {
+        number,
+        name,
+        handler,
+        args,
+        ret,
     }
 }
 
-impl<T> Try for NumericResult<T> {
-    type Output = T;
-    type Residual = NumericResult<Infallible>;
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(regs: &RegisterFrame) -> u64 {
+    let args = SyscallArgs::from(*regs);
+
+    let (pid, traced) = {
+        let mut proc = sched::current();
 
-    #[inline]
-    fn from_output(output: Self::Output) -> Self {
-        NumericResult::Ok(output)
+        proc.registers = *regs;
+
+        (proc.pid, cfg!(strace) || proc.strace)
+    };
+
+    let Some(syscall) = SYSCALLS.get(args.number as usize) else {
+        if traced {
+            println_serial!("[{}] unknown syscall {}", pid, { args.number });
+        }
+
+        return NumericResult::Err(Error::BadArgs).into_raw();
+    };
+
+    if traced {
+        println_serial!("[{}] {}({})", pid, syscall.name, DecodedArgs(syscall, &args));
     }
 
-    #[inline]
-    fn branch(self) -> ControlFlow<Self::Residual, Self::Output> {
-        match self {
-            NumericResult::Ok(t) => ControlFlow::Continue(t),
-            NumericResult::Err(num) => ControlFlow::Break(NumericResult::Err(num)),
+    let result = match validate(syscall, &args) {
+        NumericResult::Ok(()) => (syscall.handler)(&args),
+        NumericResult::Err(err) => NumericResult::Err(err),
+    };
+
+    if traced {
+        match result {
+            NumericResult::Ok(val) => {
+                println_serial!("[{}] {} = {}", pid, syscall.name, Decoded(syscall.ret, val));
+            }
+            NumericResult::Err(err) => {
+                println_serial!("[{}] {} = -{} ({})", pid, syscall.name, err.code(), err);
+            }
         }
     }
+
+    result.into_raw()
 }
 
-impl<T> FromResidual for NumericResult<T> {
-    #[inline]
-    fn from_residual(residual: NumericResult<Infallible>) -> Self {
-        match residual {
-            // This match arm shouldn't be needed, but rustc complains without it
-            NumericResult::Ok(_) => unreachable!(),
-            NumericResult::Err(num) => NumericResult::Err(num),
+/// Check arguments that are the same for all syscalls, so that handlers only check what's specific
+/// to them
+fn validate(syscall: &Syscall, args: &SyscallArgs) -> NumericResult<()> {
+    for (idx, &kind) in syscall.args.iter().enumerate() {
+        let val = args.get(idx);
+
+        match kind {
+            ArgKind::UserPtr | ArgKind::Str if val >= arch::USER_SPACE_END.0 as u64 => {
+                return NumericResult::Err(Error::NoPermissions);
+            }
+            ArgKind::Len if val > arch::USER_SPACE_END.0 as u64 => {
+                return NumericResult::Err(Error::BadArgs);
+            }
+            ArgKind::Prot if val & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 => {
+                return NumericResult::Err(Error::BadArgs);
+            }
+            _ => {}
         }
     }
+
+    NumericResult::Ok(())
 }
 
-impl FromResidual<NumericResult<Infallible>> for u64 {
-    #[inline]
-    fn from_residual(residual: NumericResult<Infallible>) -> Self {
-        match residual {
-            NumericResult::Ok(_) => unreachable!(),
-            NumericResult::Err(num) => num,
+/// Arguments of a syscall formatted according to their kinds
+struct DecodedArgs<'a>(&'a Syscall, &'a SyscallArgs);
+
+impl fmt::Display for DecodedArgs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let DecodedArgs(syscall, args) = self;
+
+        for (idx, &kind) in syscall.args.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}", Decoded(kind, args.get(idx)))?;
         }
+
+        Ok(())
     }
 }
 
-#[no_mangle]
-pub extern "C" fn syscall_dispatch(regs: &RegisterFrame) -> u64 {
-    let args = SyscallArgs::from(*regs);
+/// Argument or return value formatted according to its kind
+struct Decoded(ArgKind, u64);
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Decoded(kind, val) = *self;
+
+        match kind {
+            ArgKind::Int | ArgKind::Pid | ArgKind::Len => write!(f, "{val}"),
+            ArgKind::UserPtr => write!(f, "{val:#x}"),
+            ArgKind::Str => {
+                let mut buf = [0; STRACE_STR_LEN];
+
+                let Ok(len) = strncpy_from_user(&mut buf, VirtAddr::from_u64(val)) else {
+                    return write!(f, "{val:#x}");
+                };
+
+                let string = String::from_utf8_lossy(&buf[..len]);
+                let ellipsis = if len == buf.len() { "..." } else { "" };
+
+                write!(f, "{string:?}{ellipsis}")
+            }
+            ArgKind::Prot => {
+                let names = [
+                    (PROT_READ, "PROT_READ"),
+                    (PROT_WRITE, "PROT_WRITE"),
+                    (PROT_EXEC, "PROT_EXEC"),
+                ];
+                let mut set =
+                    names.iter().filter(|(flag, _)| val & flag != 0).map(|(_, name)| name);
+
+                match set.next() {
+                    Some(first) => write!(f, "{first}")?,
+                    None => return write!(f, "0"),
+                }
+
+                set.try_for_each(|name| write!(f, "|{name}"))
+            }
+        }
+    }
+}
+
+fn sched_yield(_args: &SyscallArgs) -> NumericResult<u64> {
+    sched::next()
+}
+
+fn exit(args: &SyscallArgs) -> NumericResult<u64> {
+    sched::exit_current(args.arg1)
+}
 
-    sched::current().registers = *regs;
+fn write(args: &SyscallArgs) -> NumericResult<u64> {
+    let mut from = VirtAddr::from_u64(args.arg1);
+    let mut left = args.arg2 as usize;
+    let mut buf = [0; WRITE_CHUNK_SIZE];
+    let mut carry = 0;
 
-    trace!("{}", args);
+    while left > 0 {
+        let len = left.min(buf.len() - carry);
+        let filled = carry + len;
 
-    match args.number {
-        SYSC_YIELD => sched::next(),
-        SYSC_WRITE => write(&args),
-        SYSC_GETCH => getch(),
-        _ => {
-            trace!("invalid syscall number");
-            SYSR_ERR_BAD_ARGS
+        copy_from_user(&mut buf[carry..filled], from).convert_err(Error::NoPermissions)?;
+
+        from = from + len;
+        left -= len;
+
+        // Character can be split between chunks, in which case it's completed with the next one
+        let valid = match str::from_utf8(&buf[..filled]) {
+            Ok(string) => string.len(),
+            Err(err) if err.error_len().is_none() && left > 0 => err.valid_up_to(),
+            Err(_) => return NumericResult::Err(Error::BadArgs),
+        };
+
+        print!("{}", unsafe { str::from_utf8_unchecked(&buf[..valid]) });
+
+        buf.copy_within(valid..filled, 0);
+        carry = filled - valid;
+    }
+
+    NumericResult::Ok(0)
+}
+
+fn wait(args: &SyscallArgs) -> NumericResult<u64> {
+    let pid = args.arg1;
+    let to = VirtAddr::from_u64(args.arg2);
+
+    loop {
+        match sched::child_exit_code(pid) {
+            Ok(Some(code)) => {
+                // Child stays a zombie if the code can't be delivered, so that waiting can be retried
+                copy_to_user(to, &code.to_ne_bytes()).convert_err(Error::NoPermissions)?;
+
+                sched::reap_child(pid);
+
+                return NumericResult::Ok(0);
+            }
+            Ok(None) => sched::block_current(State::Waiting(pid)),
+            Err(()) => return NumericResult::Err(Error::BadArgs),
         }
     }
 }
 
-fn write(args: &SyscallArgs) -> u64 {
-    let mut root_dir = sched::current().root_dir;
-    let addr = args.arg1;
-    let size = args.arg2 as usize;
-    let from = VirtAddr::from_u64(addr);
+fn getch(_args: &SyscallArgs) -> NumericResult<u64> {
+    NumericResult::Ok(serial::read().into())
+}
+
+fn sleep(args: &SyscallArgs) -> NumericResult<u64> {
+    let now = time::ticks();
+    let deadline = now.saturating_add(time::ms_to_ticks(args.arg1));
+
+    if deadline == now {
+        return NumericResult::Ok(0);
+    }
+
+    sched::sleep_until(deadline).convert_err(Error::NoResources)?;
+
+    NumericResult::Ok(0)
+}
+
+fn fork(_args: &SyscallArgs) -> NumericResult<u64> {
+    match sched::fork_current() {
+        Ok(pid) => NumericResult::Ok(pid),
+        Err(kstack::AllocError::NoSlots) => NumericResult::Err(Error::NoResources),
+        Err(kstack::AllocError::OutOfMemory) => NumericResult::Err(Error::NoMemory),
+    }
+}
+
+/// Replace the current program with one from the initial ramdisk, named by NUL-terminated string
+/// in `arg1`. Arguments and environment are NULL-terminated arrays of such strings in `arg2` and
+/// `arg3`, either of which can be NULL. Returns only on failure.
+fn exec(args: &SyscallArgs) -> NumericResult<u64> {
+    let mut buf = [0; MAX_PATH_LEN];
+
+    let len = strncpy_from_user(&mut buf, VirtAddr::from_u64(args.arg1))
+        .convert_err(Error::NoPermissions)?;
+
+    if len == buf.len() {
+        return NumericResult::Err(Error::BadArgs);
+    }
+
+    let path = str::from_utf8(&buf[..len]).convert_err(Error::BadArgs)?;
+    let program = programs::find(path).ok_or(()).convert_err(Error::BadArgs)?;
+    let image = elf::parse(program.elf).convert_err(Error::BadArgs)?;
+
+    let mut program_args = ProgramArgs::new();
+
+    copy_strings_from_user(&mut program_args, args.arg2, false)?;
+    copy_strings_from_user(&mut program_args, args.arg3, true)?;
+
+    match sched::exec_current(program.name, &image, &program_args) {
+        Ok(never) => match never {},
+        Err(LoadError::OutOfMemory) => NumericResult::Err(Error::NoMemory),
+        Err(_) => NumericResult::Err(Error::BadArgs),
+    }
+}
+
+/// Move the end of the heap to `arg1`. Returns the new end, or the current one if it can't be
+/// moved there, which is how 0 queries it.
+fn brk(args: &SyscallArgs) -> NumericResult<u64> {
+    let mut proc = sched::current();
+    let proc = &mut *proc;
+    let new = VirtAddr::from_u64(args.arg1);
+
+    if new < proc.brk_start || new > arch::USER_SPACE_END {
+        return NumericResult::Ok(proc.brk.0 as u64);
+    }
+
+    let old_end = proc.brk.page_round_up();
+    let new_end = new.page_round_up();
+
+    let moved = if new_end > old_end {
+        let perms = prot_to_permissions(PROT_READ | PROT_WRITE)?;
+        let heap = Vma::anonymous(old_end, new_end.0 - old_end.0, perms);
+
+        proc.vmas.add(heap).is_ok()
+    } else {
+        proc.vmas.unmap(&mut proc.root_dir, new_end, old_end).is_ok()
+    };
+
+    if moved {
+        proc.brk = new;
+    }
+
+    NumericResult::Ok(proc.brk.0 as u64)
+}
+
+/// Map `arg2` bytes of zeroed memory with `PROT_*` permissions in `arg3`. It's placed at `arg1`,
+/// which mustn't overlap existing mappings, or anywhere if that's 0. Returns its address.
+fn mmap(args: &SyscallArgs) -> NumericResult<u64> {
+    let perms = prot_to_permissions(args.arg3)?;
+    let len = args.arg2 as usize;
+
+    if len == 0 {
+        return NumericResult::Err(Error::BadArgs);
+    }
+
+    let size = len.next_multiple_of(mmu::PAGE_SIZE);
+    let mut proc = sched::current();
+
+    let start = if args.arg1 == 0 {
+        let free = proc.vmas.find_free(arch::USER_MMAP_START, size);
+
+        free.ok_or(()).convert_err(Error::NoMemory)?
+    } else {
+        user_range(args.arg1, args.arg2)?.0
+    };
+
+    match proc.vmas.add(Vma::anonymous(start, size, perms)) {
+        Ok(()) => NumericResult::Ok(start.0 as u64),
+        Err(AddError::Overlaps) => NumericResult::Err(Error::BadArgs),
+        Err(AddError::OutOfMemory) => NumericResult::Err(Error::NoMemory),
+    }
+}
+
+/// Unmap `arg2` bytes at `arg1`, parts of which may not be mapped
+fn munmap(args: &SyscallArgs) -> NumericResult<u64> {
+    let (start, end) = user_range(args.arg1, args.arg2)?;
+    let mut proc = sched::current();
+    let proc = &mut *proc;
+
+    proc.vmas.unmap(&mut proc.root_dir, start, end).convert_err(Error::NoMemory)?;
+
+    NumericResult::Ok(0)
+}
+
+/// Change permissions of `arg2` bytes of mapped memory at `arg1` to `PROT_*` ones in `arg3`
+fn mprotect(args: &SyscallArgs) -> NumericResult<u64> {
+    let (start, end) = user_range(args.arg1, args.arg2)?;
+    let perms = prot_to_permissions(args.arg3)?;
+    let mut proc = sched::current();
+    let proc = &mut *proc;
+
+    let mapped =
+        proc.vmas.protect(&mut proc.root_dir, start, end, perms).convert_err(Error::NoMemory)?;
+
+    if !mapped {
+        return NumericResult::Err(Error::BadArgs);
+    }
+
+    NumericResult::Ok(0)
+}
+
+/// Page-aligned range of user memory from address and length arguments. Address has to be aligned
+/// and the range non-empty.
+fn user_range(addr: u64, len: u64) -> NumericResult<(VirtAddr, VirtAddr)> {
+    let start = VirtAddr::from_u64(addr);
+    let end = start.0.checked_add(len as usize).map(VirtAddr);
+
+    match end {
+        Some(end) if start.is_page_aligned() && len > 0 && end <= arch::USER_SPACE_END => {
+            NumericResult::Ok((start, end.page_round_up()))
+        }
+        _ => NumericResult::Err(Error::BadArgs),
+    }
+}
 
-    if !root_dir.is_region_user_accessible(from, from + size) {
-        return SYSR_ERR_NO_PERMISSIONS;
+/// Memory can't be made inaccessible without unmapping it, so `PROT_READ` is required. Like ELF
+/// segments, memory can't be both writable and executable.
+fn prot_to_permissions(prot: u64) -> NumericResult<usize> {
+    if prot & PROT_READ == 0 || prot & (PROT_WRITE | PROT_EXEC) == PROT_WRITE | PROT_EXEC {
+        return NumericResult::Err(Error::BadArgs);
     }
 
-    let ptr = addr as *const u8;
+    let mut perms = mmu::PRESENT | mmu::USER_ACCESSIBLE;
 
-    unsafe {
-        let slice = slice::from_raw_parts(ptr, size);
-        let string = str::from_utf8(slice).convert_err(SYSR_ERR_BAD_ARGS)?;
+    if prot & PROT_WRITE != 0 {
+        perms |= mmu::WRITABLE;
+    }
 
-        print!("{}", string);
+    if prot & PROT_EXEC == 0 {
+        perms |= mmu::NON_EXECUTABLE;
     }
 
-    SYSR_OK
+    NumericResult::Ok(perms)
+}
+
+/// Log syscalls of the current process to serial if `arg1` is non-zero. Children inherit this.
+fn strace(args: &SyscallArgs) -> NumericResult<u64> {
+    sched::current().strace = args.arg1 != 0;
+
+    NumericResult::Ok(0)
 }
 
-fn getch() -> u64 {
-    SERIAL.lock().read_blocking().into()
+/// Copy strings from a NULL-terminated user array of pointers to them
+fn copy_strings_from_user(to: &mut ProgramArgs, array: u64, env: bool) -> NumericResult<()> {
+    if array == 0 {
+        return NumericResult::Ok(());
+    }
+
+    let mut ptr_addr = VirtAddr::from_u64(array);
+
+    loop {
+        let mut ptr = [0; size_of::<u64>()];
+
+        copy_from_user(&mut ptr, ptr_addr).convert_err(Error::NoPermissions)?;
+
+        let string_addr = u64::from_ne_bytes(ptr);
+
+        if string_addr == 0 {
+            return NumericResult::Ok(());
+        }
+
+        let spare = to.spare();
+        let len = strncpy_from_user(spare, VirtAddr::from_u64(string_addr))
+            .convert_err(Error::NoPermissions)?;
+
+        to.commit(len, env).convert_err(Error::BadArgs)?;
+
+        ptr_addr = ptr_addr + size_of::<u64>();
+    }
 }
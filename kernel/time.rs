@@ -0,0 +1,45 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Frequency of the scheduler tick, set in config.rs
+pub const HZ: u64 = parse_u64(env!("CFG_HZ"));
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Convert milliseconds to ticks, rounding up so that waiting for that many ticks never takes less
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(HZ).div_ceil(1000)
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks.saturating_mul(1000) / HZ
+}
+
+/// Called from timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+const fn parse_u64(s: &str) -> u64 {
+    let bytes = s.as_bytes();
+    let mut val = 0;
+    let mut i = 0;
+
+    assert!(!bytes.is_empty());
+
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit());
+        val = val * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+
+    val
+}
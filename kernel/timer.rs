@@ -0,0 +1,84 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::spinlock::Mutex;
+use crate::time;
+
+const MAX_TIMERS: usize = 64;
+
+static TIMERS: Mutex<TimerList> = Mutex::new(TimerList::new());
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    callback: fn(u64),
+    data: u64,
+}
+
+/// Pending timers, sorted by deadline
+struct TimerList {
+    timers: [Option<Timer>; MAX_TIMERS],
+    len: usize,
+}
+
+impl TimerList {
+    const fn new() -> Self {
+        Self {
+            timers: [None; MAX_TIMERS],
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, timer: Timer) -> Result<(), ()> {
+        if self.len == MAX_TIMERS {
+            return Err(());
+        }
+
+        // Timers with equal deadlines fire in the order they were added
+        let pos = self.timers[..self.len]
+            .iter()
+            .position(|t| t.unwrap().deadline > timer.deadline)
+            .unwrap_or(self.len);
+
+        self.timers[pos..=self.len].rotate_right(1);
+        self.timers[pos] = Some(timer);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+        let first = self.timers[0]?;
+
+        if first.deadline > now {
+            return None;
+        }
+
+        self.timers[..self.len].rotate_left(1);
+        self.timers[self.len - 1] = None;
+        self.len -= 1;
+
+        Some(first)
+    }
+}
+
+/// Call `callback(data)` from the timer interrupt once tick counter reaches `deadline`. Callbacks
+/// run with interrupts disabled and must not block.
+pub fn add(deadline: u64, callback: fn(u64), data: u64) -> Result<(), ()> {
+    TIMERS.lock().insert(Timer {
+        deadline,
+        callback,
+        data,
+    })
+}
+
+/// Run callbacks of all timers whose deadline has passed
+pub fn run_expired() {
+    let now = time::ticks();
+
+    // Lock is released before calling back, so that callbacks can add new timers
+    while let Some(timer) = TIMERS.lock().pop_expired(now) {
+        (timer.callback)(timer.data);
+    }
+}
@@ -0,0 +1,169 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Interface between the kernel and userspace: syscall numbers, constants their arguments are made
+//! of and how results are returned.
+
+#![no_std]
+#![feature(try_trait_v2)]
+
+use core::convert::Infallible;
+use core::fmt;
+use core::ops::{ControlFlow, FromResidual, Try};
+
+pub const SYSC_YIELD: u64 = 0;
+pub const SYSC_WRITE: u64 = 1;
+pub const SYSC_GETCH: u64 = 2;
+pub const SYSC_EXIT: u64 = 3;
+pub const SYSC_WAIT: u64 = 4;
+pub const SYSC_SLEEP: u64 = 5;
+pub const SYSC_FORK: u64 = 6;
+pub const SYSC_EXEC: u64 = 7;
+pub const SYSC_BRK: u64 = 8;
+pub const SYSC_MMAP: u64 = 9;
+pub const SYSC_MUNMAP: u64 = 10;
+pub const SYSC_MPROTECT: u64 = 11;
+pub const SYSC_STRACE: u64 = 12;
+
+/// Memory permissions for mmap and mprotect. Memory can only be mapped readable, and not writable
+/// and executable at once.
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Exit code reported for processes terminated by the kernel
+pub const EXIT_CODE_KILLED: u64 = u64::MAX;
+
+/// Maximum number of arguments and environment variables a program can be started with
+pub const MAX_ARGS: usize = 32;
+
+/// Maximum total size of argument and environment strings, including terminating NULs
+pub const MAX_ARGS_SIZE: usize = 2048;
+
+/// Longest program name accepted by exec
+pub const MAX_PATH_LEN: usize = 64;
+
+/// Reason a syscall failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Error {
+    NoPermissions = 1,
+    BadArgs = 2,
+    NoResources = 3,
+    NoMemory = 4,
+}
+
+impl Error {
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            1 => Some(Error::NoPermissions),
+            2 => Some(Error::BadArgs),
+            3 => Some(Error::NoResources),
+            4 => Some(Error::NoMemory),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Error::NoPermissions => "no permissions",
+            Error::BadArgs => "bad arguments",
+            Error::NoResources => "no resources",
+            Error::NoMemory => "out of memory",
+        };
+
+        write!(f, "{msg}")
+    }
+}
+
+/// Result of a syscall. It's returned in a single register, where errors are negated codes, which
+/// are too big to be mistaken for valid values.
+#[must_use]
+pub enum NumericResult<T> {
+    Ok(T),
+    Err(Error),
+}
+
+impl NumericResult<u64> {
+    pub fn from_raw(raw: u64) -> Self {
+        match Error::from_code(raw.wrapping_neg()) {
+            Some(err) => NumericResult::Err(err),
+            None => NumericResult::Ok(raw),
+        }
+    }
+
+    pub fn into_raw(self) -> u64 {
+        match self {
+            NumericResult::Ok(val) => val,
+            NumericResult::Err(err) => err.code().wrapping_neg(),
+        }
+    }
+}
+
+impl<T> From<NumericResult<T>> for Result<T, Error> {
+    fn from(result: NumericResult<T>) -> Self {
+        match result {
+            NumericResult::Ok(t) => Ok(t),
+            NumericResult::Err(err) => Err(err),
+        }
+    }
+}
+
+pub trait ConvertToNumericResult<T> {
+    fn convert_err(self, err: Error) -> NumericResult<T>;
+}
+
+impl<T, E> ConvertToNumericResult<T> for Result<T, E> {
+    fn convert_err(self, err: Error) -> NumericResult<T> {
+        match self {
+            Ok(t) => NumericResult::Ok(t),
+            Err(_) => NumericResult::Err(err),
+        }
+    }
+}
+
+impl<T> Try for NumericResult<T> {
+    type Output = T;
+    type Residual = NumericResult<Infallible>;
+
+    #[inline]
+    fn from_output(output: Self::Output) -> Self {
+        NumericResult::Ok(output)
+    }
+
+    #[inline]
+    fn branch(self) -> ControlFlow<Self::Residual, Self::Output> {
+        match self {
+            NumericResult::Ok(t) => ControlFlow::Continue(t),
+            NumericResult::Err(err) => ControlFlow::Break(NumericResult::Err(err)),
+        }
+    }
+}
+
+impl<T> FromResidual<NumericResult<Infallible>> for Result<T, Error> {
+    #[inline]
+    fn from_residual(residual: NumericResult<Infallible>) -> Self {
+        match residual {
+            NumericResult::Ok(_) => unreachable!(),
+            NumericResult::Err(err) => Err(err),
+        }
+    }
+}
+
+impl<T> FromResidual for NumericResult<T> {
+    #[inline]
+    fn from_residual(residual: NumericResult<Infallible>) -> Self {
+        match residual {
+            // This match arm shouldn't be needed, but rustc complains without it
+            NumericResult::Ok(_) => unreachable!(),
+            NumericResult::Err(err) => NumericResult::Err(err),
+        }
+    }
+}
@@ -0,0 +1,900 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Parser of the ELF executables the kernel loads. It validates everything the loader relies on
+//! and doesn't depend on the kernel, so that it can be tested on the host with `cargo test`.
+
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(test, feature(offset_of))]
+
+use core::fmt;
+use core::mem::size_of;
+
+pub type Elf64Addr = u64;
+pub type Elf64Off = u64;
+pub type Elf64Half = u16;
+pub type Elf64Word = u32;
+pub type Elf64Xword = u64;
+pub type Elf64Sxword = i64;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf64Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: Elf64Half,
+    pub e_machine: Elf64Half,
+    pub e_version: Elf64Word,
+    pub e_entry: Elf64Addr,
+    pub e_phoff: Elf64Off,
+    pub e_shoff: Elf64Off,
+    pub e_flags: Elf64Word,
+    pub e_ehsize: Elf64Half,
+    pub e_phentsize: Elf64Half,
+    pub e_phnum: Elf64Half,
+    pub e_shentsize: Elf64Half,
+    pub e_shnum: Elf64Half,
+    pub e_shstrndx: Elf64Half,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf64Phdr {
+    pub p_type: Elf64Word,
+    pub p_flags: Elf64Word,
+    pub p_offset: Elf64Off,
+    pub p_vaddr: Elf64Addr,
+    pub p_paddr: Elf64Addr,
+    pub p_filesz: Elf64Xword,
+    pub p_memsz: Elf64Xword,
+    pub p_align: Elf64Xword,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf64Shdr {
+    pub sh_name: Elf64Word,
+    pub sh_type: Elf64Word,
+    pub sh_flags: Elf64Xword,
+    pub sh_addr: Elf64Addr,
+    pub sh_offset: Elf64Off,
+    pub sh_size: Elf64Xword,
+    pub sh_link: Elf64Word,
+    pub sh_info: Elf64Word,
+    pub sh_addralign: Elf64Xword,
+    pub sh_entsize: Elf64Xword,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Elf64Rela {
+    pub r_offset: Elf64Addr,
+    pub r_info: Elf64Xword,
+    pub r_addend: Elf64Sxword,
+}
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const EI_OSABI: usize = 7;
+const EI_NIDENT: usize = 16;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFOSABI_SYSV: u8 = 0;
+
+const ET_EXEC: Elf64Half = 2;
+const ET_DYN: Elf64Half = 3;
+const EM_X86_64: Elf64Half = 62;
+const EV_CURRENT: Elf64Word = 1;
+
+pub const PT_LOAD: Elf64Word = 1;
+pub const PT_DYNAMIC: Elf64Word = 2;
+pub const PT_TLS: Elf64Word = 7;
+pub const PF_X: Elf64Word = 0b001;
+pub const PF_W: Elf64Word = 0b010;
+pub const PF_R: Elf64Word = 0b100;
+
+const DT_NULL: Elf64Sxword = 0;
+const DT_RELA: Elf64Sxword = 7;
+const DT_RELASZ: Elf64Sxword = 8;
+const DT_RELAENT: Elf64Sxword = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Granularity segments are mapped with
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Largest TLS segment accepted, far beyond what programs need
+pub const MAX_TLS_SIZE: u64 = 0x10_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// File doesn't start with the ELF magic
+    BadMagic,
+    /// File ends in the middle of a header or a table
+    Truncated,
+    /// Header field has a value the loader doesn't support
+    BadField(&'static str),
+    /// Segment contents lie outside of the file
+    SegmentOutOfFile,
+    /// Segment has more bytes in the file than in memory
+    FileSizeExceedsMemSize,
+    /// Loadable segments share a page
+    OverlappingSegments,
+    /// Segment doesn't fit below the end of user address space
+    SegmentInKernelSpace,
+    /// Segment is both writable and executable
+    WritableAndExecutable,
+    /// Dynamic section or relocation table is malformed
+    BadDynamic,
+    /// Relocation type other than the ones static-PIE executables use
+    UnsupportedRelocation(u32),
+    /// Relocation patches memory outside of loadable segments
+    RelocationOutOfSegments,
+    /// More than one TLS segment, its alignment isn't a power of two no bigger than a page, or it's
+    /// bigger than `MAX_TLS_SIZE`
+    BadTls,
+    /// Entry point isn't inside an executable loadable segment
+    BadEntry,
+}
+
+/// Loadable segment of a validated executable
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    pub vaddr: Elf64Addr,
+    pub memsz: Elf64Xword,
+    pub flags: Elf64Word,
+    /// Contents from the file, the rest up to `memsz` is zeroed
+    pub data: &'a [u8],
+}
+
+/// Initial contents of thread-local storage
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate<'a> {
+    /// No bigger than `MAX_TLS_SIZE`
+    pub memsz: Elf64Xword,
+    /// Power of two, no bigger than `PAGE_SIZE`
+    pub align: Elf64Xword,
+    /// Initialized part of TLS, the rest up to `memsz` is zeroed
+    pub data: &'a [u8],
+}
+
+/// Relocation that adds load base to `addend` and stores the result at `offset` from load base
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: Elf64Addr,
+    pub addend: Elf64Sxword,
+}
+
+/// Executable that passed validation
+pub struct Elf<'a> {
+    data: &'a [u8],
+    e_type: Elf64Half,
+    e_entry: Elf64Addr,
+    e_phoff: Elf64Off,
+    phdrs: &'a [u8],
+    relocations: &'a [u8],
+}
+
+macro_rules! read_int {
+    ($ty:ident, $in:expr) => {{
+        let int_bytes = read_bytes!(size_of::<$ty>(), $in);
+        $ty::from_le_bytes(int_bytes.try_into().unwrap())
+    }};
+}
+
+macro_rules! read_bytes {
+    ($num:expr, $in:expr) => {{
+        if $in.len() < $num {
+            return Err(ElfError::Truncated);
+        }
+
+        let (bytes, rest) = $in.split_at($num);
+        $in = rest;
+        bytes
+    }};
+}
+
+macro_rules! read_last {
+    ($ty:ident, $in:expr) => {{
+        let ret = read_int!($ty, $in);
+        _ = $in;
+        ret
+    }};
+}
+
+macro_rules! check_field {
+    ($var:expr, $expected:expr) => {{
+        if $var != $expected {
+            return Err(ElfError::BadField(stringify!($var)));
+        }
+    }};
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if !data.starts_with(b"\x7fELF") {
+            return Err(ElfError::BadMagic);
+        }
+
+        let mut input = data;
+
+        let e_ident = read_bytes!(EI_NIDENT, input);
+
+        check_field!(e_ident[EI_CLASS], ELFCLASS64);
+        check_field!(e_ident[EI_DATA], ELFDATA2LSB);
+        check_field!(e_ident[EI_OSABI], ELFOSABI_SYSV);
+
+        let e_type = read_int!(Elf64Half, input);
+        let e_machine = read_int!(Elf64Half, input);
+        let e_version = read_int!(Elf64Word, input);
+        let e_entry = read_int!(Elf64Addr, input);
+        let e_phoff = read_int!(Elf64Off, input);
+        let _e_shoff = read_int!(Elf64Off, input);
+        let _e_flags = read_int!(Elf64Word, input);
+        let _e_ehsize = read_int!(Elf64Half, input);
+        let e_phentsize = read_int!(Elf64Half, input);
+        let e_phnum = read_int!(Elf64Half, input);
+        let _e_shentsize = read_int!(Elf64Half, input);
+        let _e_shnum = read_int!(Elf64Half, input);
+        let _e_shstrndx = read_last!(Elf64Half, input);
+
+        if e_type != ET_EXEC && e_type != ET_DYN {
+            return Err(ElfError::BadField("e_type"));
+        }
+
+        check_field!(e_machine, EM_X86_64);
+        check_field!(e_version, EV_CURRENT);
+        check_field!(e_phentsize, size_of::<Elf64Phdr>() as u16);
+
+        let phdrs_size = usize::from(e_phnum) * size_of::<Elf64Phdr>();
+        let phdrs = file_range(data, e_phoff, phdrs_size as u64).ok_or(ElfError::Truncated)?;
+
+        let mut elf = Elf {
+            data,
+            e_type,
+            e_entry,
+            e_phoff,
+            phdrs,
+            relocations: &[],
+        };
+
+        elf.check_segments()?;
+        elf.check_entry()?;
+        elf.relocations = elf.find_relocations()?;
+        elf.check_relocations()?;
+
+        Ok(elf)
+    }
+
+    /// Position-independent executables are linked at 0 and can be loaded at any page
+    pub fn is_position_independent(&self) -> bool {
+        self.e_type == ET_DYN
+    }
+
+    /// Entry point, relative to load base. It's inside an executable segment.
+    pub fn entry(&self) -> Elf64Addr {
+        self.e_entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Elf64Phdr> + 'a {
+        // Table size was checked, so every entry is complete
+        self.phdrs
+            .chunks_exact(size_of::<Elf64Phdr>())
+            .filter_map(|mut input| read_phdr(&mut input).ok())
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + 'a {
+        let data = self.data;
+
+        self.program_headers().filter(|phdr| phdr.p_type == PT_LOAD).map(move |phdr| Segment {
+            vaddr: phdr.p_vaddr,
+            memsz: phdr.p_memsz,
+            flags: phdr.p_flags,
+            data: file_range(data, phdr.p_offset, phdr.p_filesz).unwrap_or(&[]),
+        })
+    }
+
+    pub fn tls(&self) -> Option<TlsTemplate<'a>> {
+        let phdr = self.program_headers().find(|phdr| phdr.p_type == PT_TLS)?;
+
+        Some(TlsTemplate {
+            memsz: phdr.p_memsz,
+            align: phdr.p_align.max(1),
+            data: file_range(self.data, phdr.p_offset, phdr.p_filesz).unwrap_or(&[]),
+        })
+    }
+
+    /// Relocations to apply after loading segments. Only needed by position-independent executables.
+    pub fn relocations(&self) -> impl Iterator<Item = Relocation> + 'a {
+        self.relocations.chunks_exact(size_of::<Elf64Rela>()).filter_map(|mut input| {
+            let rela = read_rela(&mut input).ok()?;
+
+            (rela.r_info as u32 == R_X86_64_RELATIVE).then_some(Relocation {
+                offset: rela.r_offset,
+                addend: rela.r_addend,
+            })
+        })
+    }
+
+    /// Address of program headers in memory, relative to load base, if they are loaded at all
+    pub fn program_headers_addr(&self) -> Option<Elf64Addr> {
+        self.program_headers().filter(|phdr| phdr.p_type == PT_LOAD).find_map(|phdr| {
+            let file_end = phdr.p_offset + phdr.p_filesz;
+            let phdrs_end = self.e_phoff + self.phdrs.len() as u64;
+
+            if phdr.p_offset > self.e_phoff || phdrs_end > file_end {
+                return None;
+            }
+
+            Some(phdr.p_vaddr + (self.e_phoff - phdr.p_offset))
+        })
+    }
+
+    /// Check that all segments end below `end` once loaded at `base`
+    pub fn check_placement(&self, base: Elf64Addr, end: Elf64Addr) -> Result<(), ElfError> {
+        for segment in self.segments() {
+            let seg_end =
+                base.checked_add(segment.vaddr).and_then(|a| a.checked_add(segment.memsz));
+
+            if !seg_end.is_some_and(|seg_end| seg_end <= end) {
+                return Err(ElfError::SegmentInKernelSpace);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_segments(&self) -> Result<(), ElfError> {
+        for phdr in self.program_headers() {
+            if !matches!(phdr.p_type, PT_LOAD | PT_DYNAMIC | PT_TLS) {
+                continue;
+            }
+
+            if file_range(self.data, phdr.p_offset, phdr.p_filesz).is_none() {
+                return Err(ElfError::SegmentOutOfFile);
+            }
+
+            if phdr.p_type == PT_DYNAMIC {
+                continue;
+            }
+
+            if phdr.p_filesz > phdr.p_memsz {
+                return Err(ElfError::FileSizeExceedsMemSize);
+            }
+
+            if phdr.p_type == PT_TLS {
+                let align = phdr.p_align.max(1);
+
+                if !align.is_power_of_two() || align > PAGE_SIZE || phdr.p_memsz > MAX_TLS_SIZE {
+                    return Err(ElfError::BadTls);
+                }
+
+                continue;
+            }
+
+            if page_range(&phdr).is_none() {
+                return Err(ElfError::SegmentInKernelSpace);
+            }
+
+            if phdr.p_flags & PF_W != 0 && phdr.p_flags & PF_X != 0 {
+                return Err(ElfError::WritableAndExecutable);
+            }
+        }
+
+        if self.program_headers().filter(|phdr| phdr.p_type == PT_TLS).count() > 1 {
+            return Err(ElfError::BadTls);
+        }
+
+        let loads = || self.program_headers().filter(|phdr| phdr.p_type == PT_LOAD);
+
+        for (idx, a) in loads().enumerate() {
+            for b in loads().skip(idx + 1) {
+                let (a_start, a_end) = page_range(&a).unwrap();
+                let (b_start, b_end) = page_range(&b).unwrap();
+
+                if a_start < b_end && b_start < a_end {
+                    return Err(ElfError::OverlappingSegments);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_entry(&self) -> Result<(), ElfError> {
+        let inside = self.segments().any(|segment| {
+            segment.flags & PF_X != 0
+                && segment.vaddr <= self.e_entry
+                && self.e_entry - segment.vaddr < segment.memsz
+        });
+
+        if !inside {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(())
+    }
+
+    /// Locate the relocation table through the dynamic section
+    fn find_relocations(&self) -> Result<&'a [u8], ElfError> {
+        let Some(dynamic) = self.program_headers().find(|phdr| phdr.p_type == PT_DYNAMIC) else {
+            return Ok(&[]);
+        };
+
+        let mut input = file_range(self.data, dynamic.p_offset, dynamic.p_filesz).unwrap_or(&[]);
+        let mut rela = 0;
+        let mut relasz = 0;
+        let mut relaent = size_of::<Elf64Rela>() as u64;
+
+        while !input.is_empty() {
+            let d_tag = read_int!(Elf64Sxword, input);
+            let d_val = read_int!(Elf64Xword, input);
+
+            match d_tag {
+                DT_NULL => break,
+                DT_RELA => rela = d_val,
+                DT_RELASZ => relasz = d_val,
+                DT_RELAENT => relaent = d_val,
+                _ => {}
+            }
+        }
+
+        if relasz == 0 {
+            return Ok(&[]);
+        }
+
+        if relaent != size_of::<Elf64Rela>() as u64 || relasz % relaent != 0 {
+            return Err(ElfError::BadDynamic);
+        }
+
+        // Table is referenced by its address, so it has to be found in the file through segments
+        self.program_headers()
+            .filter(|phdr| phdr.p_type == PT_LOAD)
+            .find_map(|phdr| {
+                let offset = rela.checked_sub(phdr.p_vaddr)?;
+
+                if offset.checked_add(relasz)? > phdr.p_filesz {
+                    return None;
+                }
+
+                file_range(self.data, phdr.p_offset + offset, relasz)
+            })
+            .ok_or(ElfError::BadDynamic)
+    }
+
+    fn check_relocations(&self) -> Result<(), ElfError> {
+        for mut input in self.relocations.chunks_exact(size_of::<Elf64Rela>()) {
+            let rela = read_rela(&mut input)?;
+
+            match rela.r_info as u32 {
+                R_X86_64_NONE => continue,
+                R_X86_64_RELATIVE => {}
+                r_type => return Err(ElfError::UnsupportedRelocation(r_type)),
+            }
+
+            let target_end = rela.r_offset.checked_add(size_of::<u64>() as u64);
+            let inside = self.segments().any(|segment| {
+                let seg_end = segment.vaddr + segment.memsz;
+
+                segment.vaddr <= rela.r_offset && target_end.is_some_and(|end| end <= seg_end)
+            });
+
+            if !inside {
+                return Err(ElfError::RelocationOutOfSegments);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::BadMagic => write!(f, "bad magic"),
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::BadField(field) => write!(f, "bad {field}"),
+            ElfError::SegmentOutOfFile => write!(f, "segment lies outside of file"),
+            ElfError::FileSizeExceedsMemSize => write!(f, "segment file size exceeds memory size"),
+            ElfError::OverlappingSegments => write!(f, "segments overlap"),
+            ElfError::SegmentInKernelSpace => write!(f, "segment is in kernel space"),
+            ElfError::WritableAndExecutable => write!(f, "segment is writable and executable"),
+            ElfError::BadDynamic => write!(f, "bad dynamic section"),
+            ElfError::UnsupportedRelocation(r_type) => write!(f, "unsupported relocation {r_type}"),
+            ElfError::RelocationOutOfSegments => write!(f, "relocation outside of segments"),
+            ElfError::BadTls => write!(f, "bad TLS segment"),
+            ElfError::BadEntry => write!(f, "entry point is outside of executable segments"),
+        }
+    }
+}
+
+fn read_phdr(input: &mut &[u8]) -> Result<Elf64Phdr, ElfError> {
+    Ok(Elf64Phdr {
+        p_type: read_int!(Elf64Word, *input),
+        p_flags: read_int!(Elf64Word, *input),
+        p_offset: read_int!(Elf64Off, *input),
+        p_vaddr: read_int!(Elf64Addr, *input),
+        p_paddr: read_int!(Elf64Addr, *input),
+        p_filesz: read_int!(Elf64Xword, *input),
+        p_memsz: read_int!(Elf64Xword, *input),
+        p_align: read_int!(Elf64Xword, *input),
+    })
+}
+
+fn read_rela(input: &mut &[u8]) -> Result<Elf64Rela, ElfError> {
+    Ok(Elf64Rela {
+        r_offset: read_int!(Elf64Addr, *input),
+        r_info: read_int!(Elf64Xword, *input),
+        r_addend: read_int!(Elf64Sxword, *input),
+    })
+}
+
+fn file_range(data: &[u8], offset: u64, size: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok()?;
+    let size = usize::try_from(size).ok()?;
+
+    data.get(start..start.checked_add(size)?)
+}
+
+/// Page-aligned range of addresses a loadable segment occupies, unless it wraps around
+fn page_range(phdr: &Elf64Phdr) -> Option<(u64, u64)> {
+    let start = phdr.p_vaddr / PAGE_SIZE * PAGE_SIZE;
+    let end =
+        phdr.p_vaddr.checked_add(phdr.p_memsz)?.checked_add(PAGE_SIZE - 1)? / PAGE_SIZE * PAGE_SIZE;
+
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::offset_of;
+
+    use super::*;
+
+    const PHDRS_OFFSET: usize = size_of::<Elf64Ehdr>();
+    const DATA_OFFSET: usize = 0x1000;
+    const RELA_OFFSET: usize = DATA_OFFSET + 0x40;
+    const TLS_OFFSET: usize = DATA_OFFSET + 0xc0;
+
+    /// Static-PIE executable with a text segment covering headers, a data segment with dynamic
+    /// section, two relative relocations and TLS template, the dynamic and the TLS segments
+    fn image() -> Vec<u8> {
+        let mut elf = vec![0; DATA_OFFSET + 0x100];
+        let mut e_ident = [0; EI_NIDENT];
+
+        e_ident[..4].copy_from_slice(b"\x7fELF");
+        e_ident[EI_CLASS] = ELFCLASS64;
+        e_ident[EI_DATA] = ELFDATA2LSB;
+        e_ident[EI_OSABI] = ELFOSABI_SYSV;
+
+        let ehdr = Elf64Ehdr {
+            e_ident,
+            e_type: ET_DYN,
+            e_machine: EM_X86_64,
+            e_version: EV_CURRENT,
+            e_entry: 0x100,
+            e_phoff: PHDRS_OFFSET as u64,
+            e_shoff: 0,
+            e_flags: 0,
+            e_ehsize: size_of::<Elf64Ehdr>() as u16,
+            e_phentsize: size_of::<Elf64Phdr>() as u16,
+            e_phnum: 4,
+            e_shentsize: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        };
+
+        write(&mut elf, 0, ehdr);
+
+        let data = DATA_OFFSET as u64;
+        let tls = TLS_OFFSET as u64;
+        let phdrs = [
+            phdr(PT_LOAD, PF_R | PF_X, 0, 0x200, 0x200, PAGE_SIZE),
+            phdr(PT_LOAD, PF_R | PF_W, data, 0x100, 0x200, PAGE_SIZE),
+            phdr(PT_DYNAMIC, PF_R | PF_W, data, 0x40, 0x40, 8),
+            phdr(PT_TLS, PF_R, tls, 0x10, 0x30, 8),
+        ];
+
+        write(&mut elf, PHDRS_OFFSET, phdrs);
+
+        let dynamic = [
+            [DT_RELA as u64, RELA_OFFSET as u64],
+            [DT_RELASZ as u64, 2 * size_of::<Elf64Rela>() as u64],
+            [DT_RELAENT as u64, size_of::<Elf64Rela>() as u64],
+            [DT_NULL as u64, 0],
+        ];
+
+        write(&mut elf, DATA_OFFSET, dynamic);
+
+        let relas = [(0x1080, 0x10), (0x1088, 0x20)].map(|(r_offset, r_addend)| Elf64Rela {
+            r_offset,
+            r_info: R_X86_64_RELATIVE.into(),
+            r_addend,
+        });
+
+        write(&mut elf, RELA_OFFSET, relas);
+
+        elf
+    }
+
+    /// Program header whose contents are loaded at the address they have in the file
+    fn phdr(
+        p_type: Elf64Word,
+        p_flags: Elf64Word,
+        p_offset: u64,
+        p_filesz: u64,
+        p_memsz: u64,
+        p_align: u64,
+    ) -> Elf64Phdr {
+        Elf64Phdr {
+            p_type,
+            p_flags,
+            p_offset,
+            p_vaddr: p_offset,
+            p_paddr: p_offset,
+            p_filesz,
+            p_memsz,
+            p_align,
+        }
+    }
+
+    /// Store a packed structure, whose layout matches the file on a little-endian host
+    fn write<T>(elf: &mut [u8], offset: usize, val: T) {
+        let bytes = &mut elf[offset..offset + size_of::<T>()];
+
+        unsafe { bytes.as_mut_ptr().cast::<T>().write_unaligned(val) };
+    }
+
+    fn modify<T>(elf: &mut [u8], offset: usize, f: impl FnOnce(&mut T)) {
+        let bytes = &mut elf[offset..offset + size_of::<T>()];
+        let mut val = unsafe { bytes.as_ptr().cast::<T>().read_unaligned() };
+
+        f(&mut val);
+        write(elf, offset, val);
+    }
+
+    fn set_ehdr(elf: &mut [u8], f: impl FnOnce(&mut Elf64Ehdr)) {
+        modify(elf, 0, f);
+    }
+
+    fn set_phdr(elf: &mut [u8], idx: usize, f: impl FnOnce(&mut Elf64Phdr)) {
+        modify(elf, PHDRS_OFFSET + idx * size_of::<Elf64Phdr>(), f);
+    }
+
+    fn set_rela(elf: &mut [u8], idx: usize, f: impl FnOnce(&mut Elf64Rela)) {
+        modify(elf, RELA_OFFSET + idx * size_of::<Elf64Rela>(), f);
+    }
+
+    #[test]
+    fn valid() {
+        let image = image();
+        let elf = Elf::parse(&image).unwrap();
+
+        assert!(elf.is_position_independent());
+        assert_eq!(elf.entry(), 0x100);
+        assert_eq!(elf.segments().count(), 2);
+        assert_eq!(elf.program_headers_addr(), Some(PHDRS_OFFSET as u64));
+
+        let tls = elf.tls().unwrap();
+
+        assert_eq!((tls.memsz, tls.align, tls.data.len()), (0x30, 8, 0x10));
+
+        let relocations = elf.relocations().collect::<Vec<_>>();
+
+        assert_eq!(
+            relocations,
+            [
+                Relocation {
+                    offset: 0x1080,
+                    addend: 0x10
+                },
+                Relocation {
+                    offset: 0x1088,
+                    addend: 0x20
+                },
+            ]
+        );
+
+        assert_eq!(elf.check_placement(0x5500_0000_0000, 0x8000_0000_0000), Ok(()));
+        assert_eq!(
+            elf.check_placement(0x7fff_ffff_f000, 0x8000_0000_0000),
+            Err(ElfError::SegmentInKernelSpace)
+        );
+        assert_eq!(elf.check_placement(u64::MAX, u64::MAX), Err(ElfError::SegmentInKernelSpace));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut image = image();
+        image[0] = 0;
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadMagic));
+    }
+
+    #[test]
+    fn bad_field() {
+        let mut image = image();
+        set_ehdr(&mut image, |ehdr| ehdr.e_machine = 3);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadField("e_machine")));
+    }
+
+    #[test]
+    fn truncated() {
+        let image = image();
+
+        for len in 0..image.len() {
+            assert!(Elf::parse(&image[..len]).is_err(), "accepted {len} bytes");
+        }
+
+        assert_eq!(Elf::parse(&image[..40]).err(), Some(ElfError::Truncated));
+    }
+
+    #[test]
+    fn file_size_exceeds_mem_size() {
+        let mut image = image();
+        set_phdr(&mut image, 1, |phdr| phdr.p_memsz = 0x80);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::FileSizeExceedsMemSize));
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let mut image = image();
+        set_phdr(&mut image, 1, |phdr| phdr.p_vaddr = 0x800);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::OverlappingSegments));
+    }
+
+    #[test]
+    fn segment_in_kernel_space() {
+        let mut image = image();
+        set_phdr(&mut image, 1, |phdr| phdr.p_vaddr = u64::MAX - 0x100);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::SegmentInKernelSpace));
+    }
+
+    #[test]
+    fn segment_out_of_file() {
+        let mut image = image();
+        set_phdr(&mut image, 1, |phdr| phdr.p_filesz = 0x200);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::SegmentOutOfFile));
+    }
+
+    #[test]
+    fn writable_and_executable() {
+        let mut image = image();
+        set_phdr(&mut image, 0, |phdr| phdr.p_flags |= PF_W);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::WritableAndExecutable));
+    }
+
+    #[test]
+    fn bad_relocations() {
+        let mut image = image();
+        set_rela(&mut image, 1, |rela| rela.r_info = 1);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::UnsupportedRelocation(1)));
+
+        let mut image = self::image();
+        set_rela(&mut image, 1, |rela| rela.r_offset = 0x11fc);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::RelocationOutOfSegments));
+    }
+
+    #[test]
+    fn bad_tls() {
+        let mut image = image();
+        set_phdr(&mut image, 3, |phdr| phdr.p_align = 3);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadTls));
+
+        let mut image = self::image();
+        set_phdr(&mut image, 3, |phdr| phdr.p_align = PAGE_SIZE * 2);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadTls));
+
+        let mut image = self::image();
+        set_phdr(&mut image, 3, |phdr| phdr.p_memsz = MAX_TLS_SIZE + 1);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadTls));
+
+        let mut image = self::image();
+        set_phdr(&mut image, 2, |phdr| phdr.p_type = PT_TLS);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadTls));
+    }
+
+    #[test]
+    fn bad_entry() {
+        let mut image = image();
+        set_ehdr(&mut image, |ehdr| ehdr.e_entry = 0x1080);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadEntry));
+
+        let mut image = self::image();
+        set_ehdr(&mut image, |ehdr| ehdr.e_entry = u64::MAX);
+
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadEntry));
+    }
+
+    /// Programs the initial ramdisk is made of, in the directory `make test` builds them into
+    #[test]
+    fn userspace_programs() {
+        let dir =
+            std::env::var("USERSPACE_BUNDLE").expect("USERSPACE_BUNDLE isn't set, run `make test`");
+
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let image = std::fs::read(&path).unwrap();
+            let elf = Elf::parse(&image).unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+
+            assert!(elf.is_position_independent());
+            assert!(elf.segments().any(|segment| segment.flags & PF_X != 0));
+            assert!(elf.program_headers_addr().is_some());
+            assert_eq!(elf.tls().is_some(), path.ends_with("tls"));
+            assert_eq!(elf.check_placement(0x5500_0000_0000, 0x8000_0000_0000), Ok(()));
+        }
+    }
+
+    /// Mutate random bytes of a valid image. Parser has to either reject the result or produce
+    /// something that can be iterated over without panics.
+    #[test]
+    fn fuzz() {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        let valid = image();
+
+        for _ in 0..20000 {
+            let mut image = valid.clone();
+
+            for _ in 0..1 + random() % 8 {
+                // Headers and tables are where most of the interesting bytes are
+                let pos = match random() % 4 {
+                    0 => random() % 0x100,
+                    1 => offset_of!(Elf64Ehdr, e_entry) + random() % size_of::<Elf64Addr>(),
+                    2 => DATA_OFFSET + random() % 0x100,
+                    _ => random() % image.len(),
+                };
+
+                image[pos] = random() as u8;
+            }
+
+            let len = if random() % 4 == 0 {
+                random() % image.len()
+            } else {
+                image.len()
+            };
+
+            if let Ok(elf) = Elf::parse(&image[..len]) {
+                for segment in elf.segments() {
+                    assert!(segment.data.len() as u64 <= segment.memsz);
+                }
+
+                let entry = elf.entry();
+
+                assert!(elf.segments().any(|segment| segment.flags & PF_X != 0
+                    && (segment.vaddr..segment.vaddr + segment.memsz).contains(&entry)));
+
+                if let Some(tls) = elf.tls() {
+                    assert!(tls.data.len() as u64 <= tls.memsz);
+                    assert!(tls.align.is_power_of_two());
+                    assert!(tls.memsz <= MAX_TLS_SIZE);
+                }
+
+                elf.relocations().for_each(drop);
+                elf.program_headers_addr();
+                _ = elf.check_placement(0x5500_0000_0000, 0x8000_0000_0000);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reader of the ustar archives the initial ramdisk is made of. Like the ELF parser, it doesn't
+//! depend on the kernel, so that it can be tested on the host with `cargo test`.
+
+#![cfg_attr(not(test), no_std)]
+#![feature(int_roundings)]
+
+use core::{fmt, str};
+
+/// Headers and file contents are stored in blocks of this size
+const BLOCK_SIZE: usize = 512;
+
+const NAME_FIELD: (usize, usize) = (0, 100);
+const SIZE_FIELD: (usize, usize) = (124, 12);
+const CHECKSUM_FIELD: (usize, usize) = (148, 8);
+const TYPEFLAG_OFFSET: usize = 156;
+const MAGIC_FIELD: (usize, usize) = (257, 5);
+const PREFIX_FIELD: (usize, usize) = (345, 155);
+
+const MAGIC: &[u8] = b"ustar";
+
+/// Type of regular files. Old archives mark them with NUL.
+const REGTYPE: u8 = b'0';
+const AREGTYPE: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// Archive ends in the middle of a header or file contents
+    Truncated,
+    /// Header doesn't have the ustar magic
+    BadMagic,
+    /// Header checksum doesn't match its contents
+    BadChecksum,
+    /// Header field has a value the reader doesn't support
+    BadField(&'static str),
+}
+
+/// Archive whose headers were all validated
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+/// Regular file stored in an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct File<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// Iterator over regular files of an archive, which skips directories and other special files
+#[derive(Debug, Clone)]
+pub struct Files<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+struct Entry<'a> {
+    file: File<'a>,
+    regular: bool,
+    /// Offset of the header that follows
+    next: usize,
+}
+
+impl<'a> Archive<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, TarError> {
+        let mut offset = 0;
+
+        while let Some(entry) = read_entry(data, offset)? {
+            offset = entry.next;
+        }
+
+        Ok(Archive { data })
+    }
+
+    pub fn files(&self) -> Files<'a> {
+        Files {
+            data: self.data,
+            offset: 0,
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<File<'a>> {
+        self.files().find(|file| file.name == name)
+    }
+}
+
+impl<'a> Iterator for Files<'a> {
+    type Item = File<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Headers were validated when the archive was parsed
+            let entry = read_entry(self.data, self.offset).ok()??;
+
+            self.offset = entry.next;
+
+            if entry.regular {
+                return Some(entry.file);
+            }
+        }
+    }
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TarError::Truncated => write!(f, "archive is truncated"),
+            TarError::BadMagic => write!(f, "bad magic"),
+            TarError::BadChecksum => write!(f, "bad header checksum"),
+            TarError::BadField(field) => write!(f, "bad {field}"),
+        }
+    }
+}
+
+/// Read the header at `offset` and locate contents of its file. Archive ends with a zeroed block,
+/// or without one.
+fn read_entry(data: &[u8], offset: usize) -> Result<Option<Entry>, TarError> {
+    if offset == data.len() {
+        return Ok(None);
+    }
+
+    let header = data.get(offset..offset + BLOCK_SIZE).ok_or(TarError::Truncated)?;
+
+    if header.iter().all(|&byte| byte == 0) {
+        return Ok(None);
+    }
+
+    if field(header, MAGIC_FIELD) != MAGIC {
+        return Err(TarError::BadMagic);
+    }
+
+    if octal(field(header, CHECKSUM_FIELD), "checksum")? != checksum(header) {
+        return Err(TarError::BadChecksum);
+    }
+
+    // Joining the prefix with the name would need a buffer, and short names are enough
+    if field(header, PREFIX_FIELD)[0] != 0 {
+        return Err(TarError::BadField("name prefix"));
+    }
+
+    let name = string(field(header, NAME_FIELD)).ok_or(TarError::BadField("name"))?;
+    let size = octal(field(header, SIZE_FIELD), "size")?;
+    let start = offset + BLOCK_SIZE;
+    let contents = data.get(start..start + size).ok_or(TarError::Truncated)?;
+
+    Ok(Some(Entry {
+        file: File {
+            name: name.strip_prefix("./").unwrap_or(name),
+            data: contents,
+        },
+        regular: matches!(header[TYPEFLAG_OFFSET], REGTYPE | AREGTYPE),
+        next: start + size.next_multiple_of(BLOCK_SIZE),
+    }))
+}
+
+fn field(header: &[u8], (offset, len): (usize, usize)) -> &[u8] {
+    &header[offset..offset + len]
+}
+
+/// String padded with NULs
+fn string(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+
+    str::from_utf8(&field[..len]).ok()
+}
+
+/// Octal number, which can be padded with spaces and NULs
+fn octal(field: &[u8], name: &'static str) -> Result<usize, TarError> {
+    let digits = field.split(|&byte| byte == 0 || byte == b' ').find(|digits| !digits.is_empty());
+
+    let value = digits.and_then(|digits| {
+        digits.iter().try_fold(0usize, |value, &digit| match digit {
+            b'0'..=b'7' => value.checked_mul(8).map(|value| value + usize::from(digit - b'0')),
+            _ => None,
+        })
+    });
+
+    value.ok_or(TarError::BadField(name))
+}
+
+/// Sum of header bytes, where the checksum field itself counts as spaces
+fn checksum(header: &[u8]) -> usize {
+    let (offset, len) = CHECKSUM_FIELD;
+
+    header.iter().enumerate().fold(0, |sum, (idx, &byte)| {
+        let byte = if (offset..offset + len).contains(&idx) {
+            b' '
+        } else {
+            byte
+        };
+
+        sum + usize::from(byte)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, size: usize, typeflag: u8) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_SIZE];
+
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        set_field(&mut header, SIZE_FIELD, &format!("{size:011o}"));
+        header[TYPEFLAG_OFFSET] = typeflag;
+        set_field(&mut header, MAGIC_FIELD, "ustar");
+        set_checksum(&mut header);
+
+        header
+    }
+
+    fn set_field(header: &mut [u8], (offset, _): (usize, usize), value: &str) {
+        header[offset..offset + value.len()].copy_from_slice(value.as_bytes());
+    }
+
+    fn set_checksum(header: &mut [u8]) {
+        let sum = checksum(header);
+
+        set_field(header, CHECKSUM_FIELD, &format!("{sum:06o}\0 "));
+    }
+
+    /// Files with their type flags, each padded to whole blocks
+    fn archive(files: &[(&str, &[u8], u8)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+
+        for &(name, contents, typeflag) in files {
+            archive.extend(header(name, contents.len(), typeflag));
+            archive.extend(contents);
+            archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+
+        archive
+    }
+
+    #[test]
+    fn valid() {
+        let data = archive(&[
+            ("./", &[], b'5'),
+            ("./hello", b"Hello, World!", REGTYPE),
+            ("big", &[0xaa; 1000], AREGTYPE),
+        ]);
+        let archive = Archive::parse(&data).unwrap();
+        let files = archive.files().collect::<Vec<_>>();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0],
+            File {
+                name: "hello",
+                data: b"Hello, World!"
+            }
+        );
+        assert_eq!(files[1].name, "big");
+        assert_eq!(files[1].data, [0xaa; 1000]);
+
+        assert_eq!(archive.find("hello"), Some(files[0]));
+        assert_eq!(archive.find("./"), None);
+        assert_eq!(archive.find("missing"), None);
+    }
+
+    #[test]
+    fn end_blocks() {
+        let mut data = archive(&[("file", b"data", REGTYPE)]);
+
+        assert_eq!(Archive::parse(&data).unwrap().files().count(), 1);
+        assert_eq!(Archive::parse(&[]).unwrap().files().count(), 0);
+
+        data.resize(data.len() + 2 * BLOCK_SIZE, 0);
+
+        assert_eq!(Archive::parse(&data).unwrap().files().count(), 1);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = archive(&[("file", &[0xaa; 1000], REGTYPE)]);
+
+        assert_eq!(Archive::parse(&data[..100]).unwrap_err(), TarError::Truncated);
+        assert_eq!(Archive::parse(&data[..2 * BLOCK_SIZE]).unwrap_err(), TarError::Truncated);
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut data = header("file", 0, REGTYPE);
+        data[1] = b'X';
+        assert_eq!(Archive::parse(&data).unwrap_err(), TarError::BadChecksum);
+
+        let mut data = header("file", 0, REGTYPE);
+        set_field(&mut data, MAGIC_FIELD, "xstar");
+        assert_eq!(Archive::parse(&data).unwrap_err(), TarError::BadMagic);
+
+        let mut data = header("file", 0, REGTYPE);
+        set_field(&mut data, SIZE_FIELD, "9");
+        set_checksum(&mut data);
+        assert_eq!(Archive::parse(&data).unwrap_err(), TarError::BadField("size"));
+    }
+
+    /// Initial ramdisk made by `make test` out of the userspace programs it was pointed to
+    #[test]
+    fn initramfs() {
+        let var = |name| {
+            std::env::var(name).unwrap_or_else(|_| panic!("{name} isn't set, run `make test`"))
+        };
+        let data = std::fs::read(var("INITRAMFS")).unwrap();
+        let archive = Archive::parse(&data).unwrap();
+
+        let mut names = archive.files().map(|file| file.name).collect::<Vec<_>>();
+        let mut programs = std::fs::read_dir(var("USERSPACE_BUNDLE"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        programs.sort();
+
+        assert_eq!(names, programs);
+
+        for file in archive.files() {
+            let path = std::path::Path::new(&var("USERSPACE_BUNDLE")).join(file.name);
+
+            assert!(file.data == std::fs::read(path).unwrap(), "{} differs", file.name);
+        }
+    }
+}
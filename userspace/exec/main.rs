@@ -0,0 +1,50 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use core::ffi::CStr;
+
+use ulib::println;
+
+const CHILD_FLAG: &str = "--child";
+
+#[no_mangle]
+fn main() {
+    if ulib::args().nth(1) == Some(CHILD_FLAG) {
+        return child();
+    }
+
+    let cstr = |bytes| CStr::from_bytes_with_nul(bytes).unwrap();
+    let path = cstr(b"exec\0");
+    let args = [path, cstr(b"--child\0"), cstr(b"second argument\0")];
+    let vars = [cstr(b"GREETING=Hello from parent\0")];
+
+    match ulib::fork() {
+        Err(err) => println!("Fork failed: {err}"),
+        Ok(0) => {
+            let err = ulib::exec(path, &args, &vars);
+
+            println!("Exec failed: {err}");
+
+            ulib::exit(err.code());
+        }
+        Ok(pid) => match ulib::wait(pid) {
+            Ok(code) => println!("Program {pid} exited with {code}"),
+            Err(err) => println!("Wait failed: {err}"),
+        },
+    }
+}
+
+fn child() {
+    for (idx, arg) in ulib::args().enumerate() {
+        println!("argv[{idx}] = {arg}");
+    }
+
+    for (key, value) in ulib::env() {
+        println!("{key} = {value}");
+    }
+}
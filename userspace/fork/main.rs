@@ -0,0 +1,30 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use ulib::println;
+
+#[no_mangle]
+fn main() {
+    let mut counter = 1;
+
+    match ulib::fork() {
+        Err(err) => println!("Fork failed: {err}"),
+        Ok(0) => {
+            // Stack page is shared with the parent, so this write makes a private copy of it
+            counter += 1;
+
+            println!("Child: counter = {counter}");
+
+            ulib::exit(counter);
+        }
+        Ok(pid) => match ulib::wait(pid) {
+            Ok(code) => println!("Parent: child {pid} exited with {code}, counter = {counter}"),
+            Err(err) => println!("Wait failed: {err}"),
+        },
+    }
+}
@@ -13,7 +13,7 @@ fn main() {
     loop {
         println!("Hello, World!");
 
-        ulib::sched_yield();
+        ulib::sleep(1000).expect("sleep failed");
 
         println!("Bye, World!");
     }
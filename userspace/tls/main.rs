@@ -0,0 +1,45 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use core::cell::Cell;
+
+use ulib::println;
+
+ulib::thread_local! {
+    static COUNTER: Cell<u64> = Cell::new(100);
+    static ZEROED: Cell<u64> = Cell::new(0);
+}
+
+#[no_mangle]
+fn main() {
+    println!("Thread pointer: {:#x}", ulib::thread::thread_pointer());
+
+    match ulib::fork() {
+        Err(err) => println!("Fork failed: {err}"),
+        Ok(0) => {
+            for _ in 0..3 {
+                COUNTER.with(|c| c.set(c.get() + 1));
+                ZEROED.with(|z| z.set(z.get() + 2));
+            }
+
+            report("Child");
+        }
+        Ok(pid) => {
+            let _ = ulib::wait(pid);
+
+            report("Parent");
+        }
+    }
+}
+
+fn report(who: &str) {
+    let counter = COUNTER.with(Cell::get);
+    let zeroed = ZEROED.with(Cell::get);
+
+    println!("{who}: counter = {counter}, zeroed = {zeroed}");
+}
@@ -0,0 +1,72 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::ffi::{c_char, CStr};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(ptr::null_mut());
+static ENVP: AtomicPtr<*const c_char> = AtomicPtr::new(ptr::null_mut());
+
+/// Iterator over a NULL-terminated array of strings set up by the kernel
+#[derive(Clone)]
+pub struct Strings {
+    next: *const *const c_char,
+}
+
+/// Find argv and envp in the initial stack, which starts with argc
+pub(crate) unsafe fn init(stack: *const usize) {
+    let argc = *stack;
+    let argv = stack.add(1).cast::<*const c_char>();
+
+    ARGV.store(argv.cast_mut(), Ordering::Relaxed);
+    ENVP.store(argv.add(argc + 1).cast_mut(), Ordering::Relaxed);
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next.is_null() {
+            return None;
+        }
+
+        // Arrays and strings are never modified after the program starts
+        unsafe {
+            let string = *self.next;
+
+            if string.is_null() {
+                return None;
+            }
+
+            self.next = self.next.add(1);
+
+            Some(CStr::from_ptr(string).to_str().expect("argument is not valid UTF-8"))
+        }
+    }
+}
+
+/// Arguments the program was started with, starting with its name
+///
+/// # Panics
+///
+/// Iterator panics if an argument is not valid UTF-8.
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+    }
+}
+
+/// Environment variables as key-value pairs
+///
+/// # Panics
+///
+/// Iterator panics if a variable is not valid UTF-8.
+pub fn env() -> impl Iterator<Item = (&'static str, &'static str)> {
+    let envp = Strings {
+        next: ENVP.load(Ordering::Relaxed),
+    };
+
+    envp.map(|var| var.split_once('=').unwrap_or((var, "")))
+}
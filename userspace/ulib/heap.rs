@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ptr::{self, NonNull};
+
+use crate::{mmap, mprotect, munmap, sbrk, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Block sizes of size classes. Anything bigger is mapped separately.
+const CLASS_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Amount the heap is grown by when a size class runs out of free blocks
+const REFILL_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Marks free blocks, so that freeing one again is noticed without searching free lists every time
+const FREE_MAGIC: u64 = 0xf4ee_b10c_f4ee_b10c;
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    state: UnsafeCell::new(State {
+        free: [None; CLASS_SIZES.len()],
+        stats: Stats {
+            heap_size: 0,
+            mapped: 0,
+            used: [0; CLASS_SIZES.len()],
+            allocs: 0,
+            frees: 0,
+        },
+    }),
+};
+
+/// Allocator with a free list of blocks per size class, which are carved out of memory taken from
+/// the program break. Blocks are aligned to their size. Big allocations get their own mappings.
+struct Heap {
+    state: UnsafeCell<State>,
+}
+
+// Processes are single-threaded
+unsafe impl Sync for Heap {}
+
+struct State {
+    free: [Option<NonNull<FreeBlock>>; CLASS_SIZES.len()],
+    stats: Stats,
+}
+
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+    magic: u64,
+}
+
+/// Heap usage counters
+#[derive(Clone, Copy)]
+pub struct Stats {
+    /// Bytes taken from the program break
+    pub heap_size: usize,
+    /// Bytes of big allocations, which are mapped separately
+    pub mapped: usize,
+    /// Blocks in use in each size class
+    pub used: [usize; CLASS_SIZES.len()],
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let state = &mut *self.state.get();
+
+        match class_of(layout) {
+            Some(idx) => state.alloc_block(idx),
+            None if layout.align() <= PAGE_SIZE => state.alloc_mapped(layout.size()),
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let state = &mut *self.state.get();
+
+        match class_of(layout) {
+            Some(idx) => state.free_block(idx, ptr),
+            None => state.free_mapped(ptr, layout.size()),
+        }
+    }
+}
+
+/// Size class that fits an allocation
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+
+    CLASS_SIZES.iter().position(|&class_size| size <= class_size)
+}
+
+impl State {
+    fn alloc_block(&mut self, idx: usize) -> *mut u8 {
+        if self.free[idx].is_none() && !self.refill(idx) {
+            return ptr::null_mut();
+        }
+
+        let mut block = self.free[idx].unwrap();
+        let block = unsafe { block.as_mut() };
+
+        self.free[idx] = block.next;
+        block.magic = 0;
+
+        self.stats.used[idx] += 1;
+        self.stats.allocs += 1;
+
+        (block as *mut FreeBlock).cast()
+    }
+
+    fn free_block(&mut self, idx: usize, ptr: *mut u8) {
+        let block = ptr.cast::<FreeBlock>();
+
+        // Live data can look like the marker, so it's only a hint to search the free list
+        if unsafe { (*block).magic } == FREE_MAGIC && self.is_free(idx, block) {
+            panic!("double free of {ptr:p}");
+        }
+
+        unsafe {
+            block.write(FreeBlock {
+                next: self.free[idx],
+                magic: FREE_MAGIC,
+            });
+        }
+
+        self.free[idx] = NonNull::new(block);
+
+        self.stats.used[idx] -= 1;
+        self.stats.frees += 1;
+    }
+
+    fn is_free(&self, idx: usize, block: *mut FreeBlock) -> bool {
+        let mut next = self.free[idx];
+
+        while let Some(free) = next {
+            if free.as_ptr() == block {
+                return true;
+            }
+
+            next = unsafe { free.as_ref().next };
+        }
+
+        false
+    }
+
+    /// Grow the heap and split new memory into free blocks of a size class
+    fn refill(&mut self, idx: usize) -> bool {
+        let size = CLASS_SIZES[idx];
+
+        let Ok(start) = sbrk(REFILL_SIZE as isize) else {
+            return false;
+        };
+
+        self.stats.heap_size += REFILL_SIZE;
+
+        // Break could have been moved by something else to an unaligned address
+        let first = (start as usize).next_multiple_of(size);
+        let end = start as usize + REFILL_SIZE;
+
+        for addr in (first..end - size + 1).step_by(size).rev() {
+            let block = addr as *mut FreeBlock;
+
+            unsafe {
+                block.write(FreeBlock {
+                    next: self.free[idx],
+                    magic: FREE_MAGIC,
+                });
+            }
+
+            self.free[idx] = NonNull::new(block);
+        }
+
+        true
+    }
+
+    fn alloc_mapped(&mut self, size: usize) -> *mut u8 {
+        let Ok(ptr) = mmap(ptr::null_mut(), size, PROT_READ | PROT_WRITE) else {
+            return ptr::null_mut();
+        };
+
+        self.stats.mapped += size.next_multiple_of(PAGE_SIZE);
+        self.stats.allocs += 1;
+
+        ptr
+    }
+
+    fn free_mapped(&mut self, ptr: *mut u8, size: usize) {
+        // Changing permissions fails if any of the memory isn't mapped anymore
+        if mprotect(ptr, size, PROT_READ | PROT_WRITE).is_err() {
+            panic!("double free of {ptr:p}");
+        }
+
+        // Memory stays mapped if this fails, which only wastes it
+        let _ = munmap(ptr, size);
+
+        self.stats.mapped -= size.next_multiple_of(PAGE_SIZE);
+        self.stats.frees += 1;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Heap: {} KiB from break, {} KiB mapped, {} allocs, {} frees",
+            self.heap_size / 1024,
+            self.mapped / 1024,
+            self.allocs,
+            self.frees
+        )?;
+
+        write!(f, "Blocks in use:")?;
+
+        for (size, used) in CLASS_SIZES.iter().zip(self.used) {
+            write!(f, " {size}: {used}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Current heap usage
+pub fn stats() -> Stats {
+    unsafe { (*HEAP.state.get()).stats }
+}
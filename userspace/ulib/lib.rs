@@ -3,32 +3,65 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 #![no_std]
+#![feature(allow_internal_unstable)]
 #![feature(format_args_nl)]
+#![feature(int_roundings)]
 
+pub extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
 #[cfg(target_arch = "x86_64")]
 use core::arch::asm;
+use core::ffi::{c_char, CStr};
+use core::ptr;
+
+use abi::{
+    NumericResult, SYSC_BRK, SYSC_EXEC, SYSC_EXIT, SYSC_FORK, SYSC_GETCH, SYSC_MMAP, SYSC_MPROTECT,
+    SYSC_MUNMAP, SYSC_SLEEP, SYSC_STRACE, SYSC_WAIT, SYSC_WRITE, SYSC_YIELD,
+};
+pub use args::{args, env};
 
 #[macro_use]
 pub mod print;
+pub mod args;
+pub mod heap;
+pub mod thread;
+
+pub use abi::{Error, EXIT_CODE_KILLED, MAX_ARGS, PROT_EXEC, PROT_READ, PROT_WRITE};
 
 extern "Rust" {
     fn main();
 }
 
-#[no_mangle]
-pub extern "C" fn _start(_argc: usize, _argv: usize) {
+// Kernel passes argc, argv, envp and auxv on the stack, which is only reachable before the
+// prologue of a Rust function moves the stack pointer
+#[cfg(target_arch = "x86_64")]
+core::arch::global_asm!(
+    ".globl _start",
+    "_start:",
+    "mov rdi, rsp",
+    "call {}",
+    sym start,
+);
+
+extern "C" fn start(stack: *const usize) -> ! {
     unsafe {
+        args::init(stack);
+
         main();
     }
+
+    exit(0);
 }
 
 #[cfg(target_arch = "aarch64")]
-fn syscall(_num: u64, _arg1: u64, _arg2: u64, _arg3: u64, _arg4: u64) -> u64 {
-    0
+fn syscall(_num: u64, _arg1: u64, _arg2: u64, _arg3: u64, _arg4: u64) -> NumericResult<u64> {
+    NumericResult::Ok(0)
 }
 
 #[cfg(target_arch = "x86_64")]
-fn syscall(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64 {
+fn syscall(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> NumericResult<u64> {
     let ret;
 
     unsafe {
@@ -45,50 +78,151 @@ fn syscall(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64 {
         );
     }
 
-    ret
+    NumericResult::from_raw(ret)
 }
 
 pub fn sched_yield() {
-    syscall(0, 0, 0, 0, 0);
+    let _ = syscall(SYSC_YIELD, 0, 0, 0, 0);
+}
+
+pub fn write(s: &str) -> Result<(), Error> {
+    syscall(SYSC_WRITE, s.as_ptr() as u64, s.len() as u64, 0, 0)?;
+
+    Ok(())
+}
+
+pub fn exit(code: u64) -> ! {
+    let _ = syscall(SYSC_EXIT, code, 0, 0, 0);
+
+    unreachable!();
 }
 
-pub fn write(s: &str) -> u64 {
-    syscall(1, s.as_ptr() as u64, s.len() as u64, 0, 0)
+/// Wait for a child process to terminate and return its exit code
+pub fn wait(pid: u64) -> Result<u64, Error> {
+    let mut code = 0;
+
+    syscall(SYSC_WAIT, pid, ptr::addr_of_mut!(code) as u64, 0, 0)?;
+
+    Ok(code)
+}
+
+/// Duplicate the calling process. Returns PID of the child to the parent and 0 to the child.
+pub fn fork() -> Result<u64, Error> {
+    syscall(SYSC_FORK, 0, 0, 0, 0).into()
+}
+
+/// Replace the calling program with the one at `path`, passing it given arguments and environment
+/// variables in form of `KEY=value`. Returns only on failure.
+///
+/// # Panics
+///
+/// Panics if there are more than `MAX_ARGS` arguments or environment variables.
+pub fn exec(path: &CStr, args: &[&CStr], vars: &[&CStr]) -> Error {
+    let mut argv = [ptr::null(); MAX_ARGS + 1];
+    let mut envp = [ptr::null(); MAX_ARGS + 1];
+
+    fill_ptr_array(&mut argv, args);
+    fill_ptr_array(&mut envp, vars);
+
+    let ret =
+        syscall(SYSC_EXEC, path.as_ptr() as u64, argv.as_ptr() as u64, envp.as_ptr() as u64, 0);
+
+    match ret {
+        NumericResult::Ok(_) => unreachable!(),
+        NumericResult::Err(err) => err,
+    }
+}
+
+/// Make NULL-terminated array of pointers to given strings
+fn fill_ptr_array(array: &mut [*const c_char], strings: &[&CStr]) {
+    assert!(strings.len() < array.len(), "too many strings for exec");
+
+    for (ptr, string) in array.iter_mut().zip(strings) {
+        *ptr = string.as_ptr();
+    }
+}
+
+/// Move the end of the heap to `addr`. Returns the new end, or the current one on failure.
+pub fn brk(addr: *mut u8) -> *mut u8 {
+    syscall(SYSC_BRK, addr as u64, 0, 0, 0).into_raw() as *mut u8
+}
+
+/// Grow or shrink the heap by `increment` bytes. Returns the previous end of the heap, which is the
+/// start of the new memory.
+pub fn sbrk(increment: isize) -> Result<*mut u8, Error> {
+    let old = brk(ptr::null_mut());
+    let new = old.wrapping_offset(increment);
+
+    if brk(new) != new {
+        return Err(Error::NoMemory);
+    }
+
+    Ok(old)
+}
+
+/// Map `len` bytes of zeroed memory with `PROT_*` permissions, at `addr` or, if it's null, wherever
+/// there's room
+pub fn mmap(addr: *mut u8, len: usize, prot: u64) -> Result<*mut u8, Error> {
+    let addr = syscall(SYSC_MMAP, addr as u64, len as u64, prot, 0)?;
+
+    Ok(addr as *mut u8)
+}
+
+pub fn munmap(addr: *mut u8, len: usize) -> Result<(), Error> {
+    syscall(SYSC_MUNMAP, addr as u64, len as u64, 0, 0)?;
+
+    Ok(())
+}
+
+pub fn mprotect(addr: *mut u8, len: usize, prot: u64) -> Result<(), Error> {
+    syscall(SYSC_MPROTECT, addr as u64, len as u64, prot, 0)?;
+
+    Ok(())
+}
+
+/// Suspend execution for at least `ms` milliseconds
+pub fn sleep(ms: u64) -> Result<(), Error> {
+    syscall(SYSC_SLEEP, ms, 0, 0, 0)?;
+
+    Ok(())
+}
+
+/// Log syscalls of the calling process and its future children to serial
+pub fn strace(enable: bool) {
+    let _ = syscall(SYSC_STRACE, enable.into(), 0, 0, 0);
 }
 
 pub fn getch(echo: bool) -> u64 {
-    let ch = syscall(2, 0, 0, 0, 0);
+    let ch = syscall(SYSC_GETCH, 0, 0, 0, 0).into_raw();
 
     if echo {
+        // Bytes above ASCII map to characters that take two bytes in UTF-8
         let c = ch as u8 as char;
-        let t = &mut [0];
+        let t = &mut [0; 2];
         let s = c.encode_utf8(t);
 
-        write(s);
+        let _ = write(s);
     }
 
     ch
 }
 
-pub fn readline<'s>() -> &'s str {
-    static mut BUF: [u8; 128] = [0; 128];
-    let mut idx = 0;
+/// Read a line from the console, echoing it back. Invalid UTF-8 is replaced with U+FFFD.
+pub fn readline() -> String {
+    let mut line = Vec::new();
 
-    unsafe {
-        while idx < BUF.len() {
-            let ch = getch(true) as u8;
+    loop {
+        let ch = getch(true) as u8;
 
-            // Enter
-            if ch == 13 {
-                break;
-            }
-
-            BUF[idx] = ch;
-            idx += 1;
+        // Enter
+        if ch == 13 {
+            break;
         }
 
-        core::str::from_utf8(&BUF).unwrap_or("invalid input")
+        line.push(ch);
     }
+
+    String::from_utf8_lossy(&line).into_owned()
 }
 
 #[panic_handler]
@@ -8,8 +8,7 @@ struct SyscallWriter;
 
 impl fmt::Write for SyscallWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        super::write(s);
-        Ok(())
+        super::write(s).map_err(|_| fmt::Error)
     }
 }
 
@@ -0,0 +1,63 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/// Handle to a variable declared with `thread_local!`. Every thread gets its own copy of the
+/// variable, initialized by the kernel from the program's TLS template.
+pub struct LocalKey<T: 'static> {
+    get: fn() -> *const T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(get: fn() -> *const T) -> Self {
+        LocalKey { get }
+    }
+
+    /// Call `f` with a reference to the current thread's copy of the variable
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        f(unsafe { &*(self.get)() })
+    }
+}
+
+/// Declare thread-local statics, similarly to the macro of the same name in `std`. Initializers
+/// must be constant, since the values are laid out in the executable's TLS segment.
+#[macro_export]
+#[allow_internal_unstable(thread_local)]
+macro_rules! thread_local {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])* $vis static $name: $crate::thread::LocalKey<$t> = {
+            #[thread_local]
+            static VAL: $t = $init;
+
+            fn get() -> *const $t {
+                ::core::ptr::addr_of!(VAL)
+            }
+
+            $crate::thread::LocalKey::new(get)
+        };
+
+        $crate::thread_local!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init;);
+    };
+}
+
+/// Address of the current thread control block, which starts with a pointer to itself
+#[cfg(target_arch = "x86_64")]
+pub fn thread_pointer() -> usize {
+    let tp;
+
+    unsafe {
+        core::arch::asm!("mov {}, fs:0", out(reg) tp, options(nostack, readonly, preserves_flags));
+    }
+
+    tp
+}